@@ -1,3 +1,7 @@
+use magnus::{Error, RString};
+
+use crate::util::build_error;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 #[magnus::wrap(class = "TreeStump::Point", free_immediately)]
 pub struct Point {
@@ -32,6 +36,65 @@ impl Point {
             column: self.column,
         }
     }
+
+    // Columns of points are byte offsets into a UTF-8 line. These helpers
+    // convert them from/to UTF-16 code units and characters, as used by LSP.
+
+    pub fn utf16_column(&self, source: RString) -> Result<usize, Error> {
+        // SAFETY: the slice is not used after Ruby code can run again.
+        let text = self.line_prefix(unsafe { source.as_slice() })?;
+        Ok(String::from_utf8_lossy(text).encode_utf16().count())
+    }
+
+    pub fn char_column(&self, source: RString) -> Result<usize, Error> {
+        // SAFETY: the slice is not used after Ruby code can run again.
+        let text = self.line_prefix(unsafe { source.as_slice() })?;
+        Ok(String::from_utf8_lossy(text).chars().count())
+    }
+
+    pub fn from_utf16_column(source: RString, row: usize, column: usize) -> Result<Self, Error> {
+        // SAFETY: the slice is not used after Ruby code can run again.
+        Self::from_column(unsafe { source.as_slice() }, row, column, char::len_utf16)
+    }
+
+    pub fn from_char_column(source: RString, row: usize, column: usize) -> Result<Self, Error> {
+        // SAFETY: the slice is not used after Ruby code can run again.
+        Self::from_column(unsafe { source.as_slice() }, row, column, |_| 1)
+    }
+
+    fn line_prefix<'a>(&self, source: &'a [u8]) -> Result<&'a [u8], Error> {
+        let line = line_at(source, self.row)
+            .ok_or_else(|| build_error(format!("Row {} is out of source", self.row)))?;
+        line.get(..self.column).ok_or_else(|| {
+            build_error(format!("Column {} is out of row {}", self.column, self.row))
+        })
+    }
+
+    // Columns past the end of the line are clamped to it, as LSP requires.
+    fn from_column(
+        source: &[u8],
+        row: usize,
+        column: usize,
+        width: fn(char) -> usize,
+    ) -> Result<Self, Error> {
+        let line = line_at(source, row)
+            .ok_or_else(|| build_error(format!("Row {} is out of source", row)))?;
+        let line = String::from_utf8_lossy(line);
+        let mut units = 0;
+        let mut byte_column = 0;
+        for c in line.chars() {
+            if units >= column {
+                break;
+            }
+            units += width(c);
+            byte_column += c.len_utf8();
+        }
+        Ok(Self::new(row, byte_column))
+    }
+}
+
+fn line_at(source: &[u8], row: usize) -> Option<&[u8]> {
+    source.split(|&b| b == b'\n').nth(row)
 }
 
 impl From<tree_sitter::Point> for Point {
@@ -89,6 +152,15 @@ impl Range {
     pub fn to_s(&self) -> String {
         format!("({}..{})", self.start_point.to_s(), self.end_point.to_s())
     }
+
+    pub fn into_raw(self) -> tree_sitter::Range {
+        tree_sitter::Range {
+            start_byte: self.start_byte,
+            end_byte: self.end_byte,
+            start_point: self.start_point.into_raw(),
+            end_point: self.end_point.into_raw(),
+        }
+    }
 }
 
 impl From<tree_sitter::Range> for Range {
@@ -101,3 +173,81 @@ impl From<tree_sitter::Range> for Range {
         }
     }
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[magnus::wrap(class = "TreeStump::InputEdit", free_immediately)]
+pub struct InputEdit {
+    pub start_byte: usize,
+    pub old_end_byte: usize,
+    pub new_end_byte: usize,
+    pub start_position: Point,
+    pub old_end_position: Point,
+    pub new_end_position: Point,
+}
+
+impl InputEdit {
+    pub fn new(
+        start_byte: usize,
+        old_end_byte: usize,
+        new_end_byte: usize,
+        start_position: &Point,
+        old_end_position: &Point,
+        new_end_position: &Point,
+    ) -> Self {
+        Self {
+            start_byte,
+            old_end_byte,
+            new_end_byte,
+            start_position: start_position.to_owned(),
+            old_end_position: old_end_position.to_owned(),
+            new_end_position: new_end_position.to_owned(),
+        }
+    }
+
+    pub fn get_start_byte(&self) -> usize {
+        self.start_byte
+    }
+
+    pub fn get_old_end_byte(&self) -> usize {
+        self.old_end_byte
+    }
+
+    pub fn get_new_end_byte(&self) -> usize {
+        self.new_end_byte
+    }
+
+    pub fn get_start_position(&self) -> Point {
+        self.start_position
+    }
+
+    pub fn get_old_end_position(&self) -> Point {
+        self.old_end_position
+    }
+
+    pub fn get_new_end_position(&self) -> Point {
+        self.new_end_position
+    }
+
+    pub fn inspect(&self) -> String {
+        format!(
+            "#<InputEdit({}, {}, {}, {:?}, {:?}, {:?})>",
+            self.start_byte,
+            self.old_end_byte,
+            self.new_end_byte,
+            self.start_position,
+            self.old_end_position,
+            self.new_end_position
+        )
+    }
+
+    pub fn into_raw(self) -> tree_sitter::InputEdit {
+        tree_sitter::InputEdit {
+            start_byte: self.start_byte,
+            old_end_byte: self.old_end_byte,
+            new_end_byte: self.new_end_byte,
+            start_position: self.start_position.into_raw(),
+            old_end_position: self.old_end_position.into_raw(),
+            new_end_position: self.new_end_position.into_raw(),
+        }
+    }
+}
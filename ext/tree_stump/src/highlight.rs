@@ -0,0 +1,161 @@
+use magnus::{
+    scan_args::{get_kwargs, scan_args},
+    typed_data,
+    value::InnerRef,
+    Class, Error, RArray, RHash, Ruby, Symbol, Value,
+};
+use tree_sitter_highlight::{HighlightConfiguration, HighlightEvent};
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::language::Language;
+use crate::util::{build_error, build_query_error, try_borrow, try_borrow_mut};
+use crate::{find_language, HIGHLIGHT_EVENT_CLASS};
+
+#[magnus::wrap(class = "TreeStump::Highlighter", free_immediately)]
+pub struct Highlighter {
+    raw_highlighter: RefCell<tree_sitter_highlight::Highlighter>,
+    language_name: String,
+    config: Arc<HighlightConfiguration>,
+    injections: RefCell<HashMap<String, Arc<HighlightConfiguration>>>,
+    highlight_names: Vec<String>,
+    // Keeps the grammar libraries of all configurations loaded.
+    languages: RefCell<Vec<Language>>,
+}
+
+struct Queries {
+    highlights: String,
+    injections: String,
+    locals: String,
+}
+
+impl Queries {
+    fn from_kwargs(kwargs: RHash) -> Result<Self, Error> {
+        let kwargs = get_kwargs::<_, (String,), (Option<String>, Option<String>), ()>(
+            kwargs,
+            &["highlights"],
+            &["injections", "locals"],
+        )?;
+        let (highlights,) = kwargs.required;
+        let (injections, locals) = kwargs.optional;
+        Ok(Self {
+            highlights,
+            injections: injections.unwrap_or_default(),
+            locals: locals.unwrap_or_default(),
+        })
+    }
+
+    fn build_config(&self, lang: &str) -> Result<(HighlightConfiguration, Language), Error> {
+        let language = find_language(lang)?;
+        let config = HighlightConfiguration::new(
+            language.raw_language.clone(),
+            lang,
+            &self.highlights,
+            &self.injections,
+            &self.locals,
+        )
+        .map_err(|e| build_query_error(e.to_string()))?;
+        Ok((config, language))
+    }
+}
+
+impl Highlighter {
+    pub fn new(args: &[Value]) -> Result<Self, Error> {
+        let args = scan_args::<(String,), (), (), (), RHash, ()>(args)?;
+        let (lang,) = args.required;
+        let highlight_names = args
+            .keywords
+            .delete::<_, Option<Vec<String>>>(Symbol::new("highlight_names"))?;
+        let queries = Queries::from_kwargs(args.keywords)?;
+        let (mut config, language) = queries.build_config(&lang)?;
+
+        // By default every capture of the highlights query is reported as is.
+        let highlight_names = highlight_names.unwrap_or_else(|| {
+            config
+                .names()
+                .iter()
+                .map(|name| name.to_string())
+                .filter(|name| !name.starts_with("injection.") && !name.starts_with("local."))
+                .collect()
+        });
+        config.configure(&highlight_names);
+
+        Ok(Self {
+            raw_highlighter: RefCell::new(tree_sitter_highlight::Highlighter::new()),
+            language_name: lang,
+            config: Arc::new(config),
+            injections: RefCell::new(HashMap::new()),
+            highlight_names,
+            languages: RefCell::new(vec![language]),
+        })
+    }
+
+    pub fn language_name(&self) -> String {
+        self.language_name.clone()
+    }
+
+    pub fn highlight_names(&self) -> Vec<String> {
+        self.highlight_names.clone()
+    }
+
+    pub fn add_injection(&self, args: &[Value]) -> Result<(), Error> {
+        let args = scan_args::<(String,), (), (), (), RHash, ()>(args)?;
+        let (lang,) = args.required;
+        let queries = Queries::from_kwargs(args.keywords)?;
+        let (mut config, language) = queries.build_config(&lang)?;
+        config.configure(&self.highlight_names);
+        try_borrow_mut(&self.injections, "Highlighter")?.insert(lang, Arc::new(config));
+        try_borrow_mut(&self.languages, "Highlighter")?.push(language);
+        Ok(())
+    }
+
+    pub fn highlight(
+        ruby: &Ruby,
+        rb_self: typed_data::Obj<Self>,
+        source: String,
+    ) -> Result<RArray, Error> {
+        let mut raw_highlighter = try_borrow_mut(&rb_self.raw_highlighter, "Highlighter")?;
+        let injections = try_borrow(&rb_self.injections, "Highlighter")?;
+        let config = &*rb_self.config;
+
+        let events = raw_highlighter
+            .highlight(config, source.as_bytes(), None, |name| {
+                if name == rb_self.language_name {
+                    Some(config)
+                } else {
+                    injections.get(name).map(|config| &**config)
+                }
+            })
+            .map_err(|e| build_error(e.to_string()))?;
+
+        let struct_class = HIGHLIGHT_EVENT_CLASS.get_inner_ref_with(ruby);
+        let array = ruby.ary_new();
+        for event in events {
+            let event = event.map_err(|e| build_error(e.to_string()))?;
+            let value = match event {
+                HighlightEvent::Source { start, end } => struct_class.new_instance((
+                    ruby.to_symbol("source"),
+                    ruby.qnil(),
+                    start,
+                    end,
+                ))?,
+                HighlightEvent::HighlightStart(highlight) => struct_class.new_instance((
+                    ruby.to_symbol("start"),
+                    rb_self.highlight_names[highlight.0].as_str(),
+                    ruby.qnil(),
+                    ruby.qnil(),
+                ))?,
+                HighlightEvent::HighlightEnd => struct_class.new_instance((
+                    ruby.to_symbol("end"),
+                    ruby.qnil(),
+                    ruby.qnil(),
+                    ruby.qnil(),
+                ))?,
+            };
+            array.push(value)?;
+        }
+        Ok(array)
+    }
+}
@@ -1,8 +1,182 @@
+use libloading::Library;
+use magnus::{value::InnerRef, Class, Error, RArray, Ruby};
+
 use std::cell::RefCell;
+use std::sync::Arc;
+
+use crate::util::build_error;
+use crate::NODE_KIND_CLASS;
+
+// The leading fields of `struct TSLanguage` (and `TSSymbolMetadata` below) as
+// declared in `lib/src/parser.h` of tree-sitter 0.22, which generated grammars
+// of ABI versions 13 and 14 are compiled against. tree-sitter 0.22 has no
+// public API for supertypes, so they are read from the symbol metadata.
+const RAW_LANGUAGE_VERSIONS: std::ops::RangeInclusive<usize> = 13..=14;
+
+#[repr(C)]
+#[allow(dead_code)]
+struct RawLanguageHeader {
+    version: u32,
+    symbol_count: u32,
+    alias_count: u32,
+    token_count: u32,
+    external_token_count: u32,
+    state_count: u32,
+    large_state_count: u32,
+    production_id_count: u32,
+    field_count: u32,
+    max_alias_sequence_length: u16,
+    parse_table: *const u16,
+    small_parse_table: *const u16,
+    small_parse_table_map: *const u32,
+    parse_actions: *const std::ffi::c_void,
+    symbol_names: *const *const std::ffi::c_char,
+    field_names: *const *const std::ffi::c_char,
+    field_map_slices: *const std::ffi::c_void,
+    field_map_entries: *const std::ffi::c_void,
+    symbol_metadata: *const RawSymbolMetadata,
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct RawSymbolMetadata {
+    visible: bool,
+    named: bool,
+    supertype: bool,
+}
+
+#[derive(Clone)]
+#[magnus::wrap(class = "TreeStump::Language", free_immediately)]
+pub struct Language {
+    pub raw_language: tree_sitter::Language,
+    // Keeps the grammar's shared library loaded while the language is in use.
+    pub library: Arc<Library>,
+}
+
+impl Language {
+    pub fn new(raw_language: tree_sitter::Language, library: Arc<Library>) -> Self {
+        Self {
+            raw_language,
+            library,
+        }
+    }
+
+    pub fn version(&self) -> usize {
+        self.raw_language.version()
+    }
+
+    pub fn node_kind_count(&self) -> usize {
+        self.raw_language.node_kind_count()
+    }
+
+    pub fn parse_state_count(&self) -> usize {
+        self.raw_language.parse_state_count()
+    }
+
+    pub fn node_kind_for_id(&self, id: u16) -> Option<&'static str> {
+        self.raw_language.node_kind_for_id(id)
+    }
+
+    pub fn id_for_node_kind(&self, kind: String, named: bool) -> u16 {
+        self.raw_language.id_for_node_kind(kind.as_str(), named)
+    }
+
+    pub fn node_kind_is_named(&self, id: u16) -> bool {
+        self.raw_language.node_kind_is_named(id)
+    }
+
+    pub fn node_kind_is_visible(&self, id: u16) -> bool {
+        self.raw_language.node_kind_is_visible(id)
+    }
+
+    pub fn field_count(&self) -> usize {
+        self.raw_language.field_count()
+    }
+
+    pub fn field_name_for_id(&self, id: u16) -> Option<&'static str> {
+        self.raw_language.field_name_for_id(id)
+    }
+
+    pub fn field_id_for_name(&self, name: String) -> Option<u16> {
+        self.raw_language
+            .field_id_for_name(name.as_str())
+            .map(|id| id.into())
+    }
+
+    pub fn next_state(&self, state: u16, id: u16) -> u16 {
+        self.raw_language.next_state(state, id)
+    }
+
+    pub fn lookahead_iterator(&self, state: u16) -> Option<LookaheadIterator> {
+        self.raw_language
+            .lookahead_iterator(state)
+            .map(|raw_iterator| LookaheadIterator {
+                raw_iterator: RefCell::new(raw_iterator),
+                _library: Some(Arc::clone(&self.library)),
+            })
+    }
+
+    pub fn inspect(&self) -> String {
+        format!(
+            "#<TreeStump::Language version={} node_kind_count={} field_count={}>",
+            self.version(),
+            self.node_kind_count(),
+            self.field_count()
+        )
+    }
+
+    pub fn node_kinds(ruby: &Ruby, rb_self: &Self) -> Result<RArray, Error> {
+        let language = &rb_self.raw_language;
+        let struct_class = NODE_KIND_CLASS.get_inner_ref_with(ruby);
+        let array = ruby.ary_new_capa(language.node_kind_count());
+        for id in 0..language.node_kind_count() as u16 {
+            let Some(name) = language.node_kind_for_id(id) else {
+                continue;
+            };
+            array.push(struct_class.new_instance((
+                id,
+                name,
+                language.node_kind_is_named(id),
+                language.node_kind_is_visible(id),
+            ))?)?;
+        }
+        Ok(array)
+    }
+
+    // Field ids start at 1, so the name of field `id` is at index `id - 1`.
+    pub fn fields(&self) -> Vec<&'static str> {
+        (1..=self.raw_language.field_count() as u16)
+            .filter_map(|id| self.raw_language.field_name_for_id(id))
+            .collect()
+    }
+
+    pub fn supertypes(&self) -> Result<Vec<&'static str>, Error> {
+        if !RAW_LANGUAGE_VERSIONS.contains(&self.version()) {
+            return Err(build_error(format!(
+                "Supertypes are not supported for language ABI version {} (supported: {}..{})",
+                self.version(),
+                RAW_LANGUAGE_VERSIONS.start(),
+                RAW_LANGUAGE_VERSIONS.end()
+            )));
+        }
+        // SAFETY: grammars of the versions checked above start `TSLanguage`
+        // with the fields of `RawLanguageHeader`.
+        let header =
+            unsafe { &*(self.raw_language.clone().into_raw() as *const RawLanguageHeader) };
+        Ok((0..header.symbol_count as u16)
+            // SAFETY: `symbol_metadata` has an entry for each of the
+            // `symbol_count` symbols, and `id < symbol_count`.
+            .filter(|&id| unsafe { (*header.symbol_metadata.add(id as usize)).supertype })
+            .filter_map(|id| self.raw_language.node_kind_for_id(id))
+            .collect())
+    }
+}
 
 #[magnus::wrap(class = "TreeStump::LanguageRef", free_immediately, unsafe_generics)]
 pub struct LanguageRef<'a> {
     pub raw_language_ref: tree_sitter::LanguageRef<'a>,
+    // The library of the tree it came from, handed on to lookahead iterators.
+    pub library: Option<Arc<Library>>,
 }
 unsafe impl Send for LanguageRef<'_> {}
 
@@ -54,6 +228,7 @@ impl<'a> LanguageRef<'a> {
             .lookahead_iterator(state)
             .map(|raw_iterator| LookaheadIterator {
                 raw_iterator: RefCell::new(raw_iterator),
+                _library: self.library.clone(),
             })
     }
 }
@@ -61,6 +236,7 @@ impl<'a> LanguageRef<'a> {
 #[magnus::wrap(class = "TreeStump::LookaheadIterator")]
 pub struct LookaheadIterator {
     raw_iterator: RefCell<tree_sitter::LookaheadIterator>,
+    _library: Option<Arc<Library>>,
 }
 
 impl LookaheadIterator {
@@ -1,53 +1,180 @@
-use magnus::{function, method, prelude::*, typed_data, value::Lazy, Error, RClass, Ruby};
+use magnus::{
+    function, method,
+    prelude::*,
+    scan_args::{get_kwargs, scan_args},
+    typed_data,
+    value::Lazy,
+    Error, RClass, RHash, Ruby, Value,
+};
 
 use libloading::Library;
 use tree_sitter::ffi::TSLanguage;
 
 use std::collections::HashMap;
-use std::sync::Mutex;
 use std::sync::OnceLock;
+use std::sync::{Arc, Mutex, Weak};
 
 mod data;
+mod highlight;
 mod language;
 mod parser;
+mod predicate;
 mod query;
+mod tags;
 mod tree;
 mod util;
 
-use crate::language::{LanguageRef, LookaheadIterator};
+use crate::highlight::Highlighter;
+use crate::language::{Language, LanguageRef, LookaheadIterator};
 use crate::parser::Parser;
 use crate::query::{Query, QueryCursor, QueryMatch};
+use crate::tags::Tagger;
 use crate::tree::{Node, Tree, TreeCursor};
 
-pub static LANG_LIBRARIES: OnceLock<Mutex<HashMap<String, Library>>> = OnceLock::new();
-pub static LANG_LANGUAGES: OnceLock<Mutex<HashMap<String, tree_sitter::Language>>> =
-    OnceLock::new();
+// Keyed by the canonical path of the shared library. Languages, parsers and
+// trees hold strong references, so a library is unloaded once none of them
+// uses it anymore.
+pub static LANG_LIBRARIES: OnceLock<Mutex<HashMap<String, Weak<Library>>>> = OnceLock::new();
+pub static LANG_LANGUAGES: OnceLock<Mutex<HashMap<String, Language>>> = OnceLock::new();
 
 pub static QUERY_CAPTURE_CLASS: Lazy<RClass> =
     Lazy::new(|ruby| ruby.define_struct(None, ("node", "index")).unwrap());
 
-fn register_lang(lang: String, path: String) -> () {
-    let func_name = String::from("tree_sitter_") + &lang;
-    let language;
+pub static QUERY_PREDICATE_CLASS: Lazy<RClass> =
+    Lazy::new(|ruby| ruby.define_struct(None, ("operator", "args")).unwrap());
 
-    let libraries = LANG_LIBRARIES.get_or_init(|| Mutex::new(HashMap::new()));
-    let languages = LANG_LANGUAGES.get_or_init(|| Mutex::new(HashMap::new()));
+pub static QUERY_PROPERTY_CLASS: Lazy<RClass> = Lazy::new(|ruby| {
+    ruby.define_struct(None, ("key", "value", "capture_id"))
+        .unwrap()
+});
+
+pub static HIGHLIGHT_EVENT_CLASS: Lazy<RClass> = Lazy::new(|ruby| {
+    ruby.define_struct(None, ("type", "name", "start_byte", "end_byte"))
+        .unwrap()
+});
+
+pub static TAG_CLASS: Lazy<RClass> = Lazy::new(|ruby| {
+    ruby.define_struct(
+        None,
+        (
+            "kind",
+            "name",
+            "is_definition",
+            "docs",
+            "line",
+            "byte_range",
+            "name_range",
+        ),
+    )
+    .unwrap()
+});
+
+pub static NODE_KIND_CLASS: Lazy<RClass> = Lazy::new(|ruby| {
+    ruby.define_struct(None, ("id", "name", "named", "visible"))
+        .unwrap()
+});
 
-    unsafe {
-        let mut libraries = libraries.lock().unwrap();
-        let lib = libraries.entry(lang.clone()).or_insert_with(|| {
-            let loaded = Library::new(path).expect("Failed to load library");
-            loaded
-        });
+pub fn find_language(lang: &str) -> Result<Language, Error> {
+    let languages = LANG_LANGUAGES.get_or_init(|| Mutex::new(HashMap::new()));
+    let languages = languages.lock().unwrap();
+    languages
+        .get(lang)
+        .cloned()
+        .ok_or_else(|| util::build_error(format!("Language {} is not registered", lang)))
+}
 
+fn load_language(
+    lib: &Library,
+    func_name: &str,
+    path: &str,
+) -> Result<tree_sitter::Language, Error> {
+    let language = unsafe {
         let func: libloading::Symbol<unsafe extern "C" fn() -> *const TSLanguage> =
-            lib.get(func_name.as_bytes()).unwrap();
+            lib.get(func_name.as_bytes()).map_err(|e| {
+                util::build_symbol_not_found_error(format!(
+                    "Symbol {} is not found in {}: {}",
+                    func_name, path, e
+                ))
+            })?;
+        tree_sitter::Language::from_raw(func())
+    };
+
+    let version = language.version();
+    if !(tree_sitter::MIN_COMPATIBLE_LANGUAGE_VERSION..=tree_sitter::LANGUAGE_VERSION)
+        .contains(&version)
+    {
+        return Err(util::build_language_load_error(format!(
+            "Incompatible language version {} of {} in {}. Expected {} to {}",
+            version,
+            func_name,
+            path,
+            tree_sitter::MIN_COMPATIBLE_LANGUAGE_VERSION,
+            tree_sitter::LANGUAGE_VERSION
+        )));
+    }
 
-        language = tree_sitter::Language::from_raw(func());
+    Ok(language)
+}
+
+fn register_lang(args: &[Value]) -> Result<(), Error> {
+    let args = scan_args::<(String, String), (), (), (), RHash, ()>(args)?;
+    let (lang, path) = args.required;
+    let kwargs = get_kwargs::<_, (), (Option<String>,), ()>(args.keywords, &[], &["symbol"])?;
+    let (symbol,) = kwargs.optional;
+    let func_name = symbol.unwrap_or_else(|| String::from("tree_sitter_") + &lang);
+
+    // Libraries are keyed by their real path, so languages exported from the same
+    // shared object (e.g. tsx and typescript) share one handle.
+    let library_key = std::fs::canonicalize(&path)
+        .map(|p| p.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| path.clone());
+
+    let libraries = LANG_LIBRARIES.get_or_init(|| Mutex::new(HashMap::new()));
+    let languages = LANG_LANGUAGES.get_or_init(|| Mutex::new(HashMap::new()));
 
-        let mut languages = languages.lock().unwrap();
-        languages.insert(lang.to_string(), language);
+    let mut libraries = libraries.lock().unwrap();
+    let library = match libraries.get(&library_key).and_then(Weak::upgrade) {
+        Some(library) => library,
+        None => Arc::new(unsafe { Library::new(&path) }.map_err(|e| {
+            util::build_language_load_error(format!(
+                "Failed to load {} (expected to export {}): {}",
+                path, func_name, e
+            ))
+        })?),
     };
+    // A newly loaded library is dropped again when it has no usable language.
+    let raw_language = load_language(&library, &func_name, &path)?;
+    libraries.insert(library_key, Arc::downgrade(&library));
+
+    let mut languages = languages.lock().unwrap();
+    languages.insert(lang, Language::new(raw_language, library));
+    Ok(())
+}
+
+fn unregister_lang(lang: String) -> bool {
+    let languages = LANG_LANGUAGES.get_or_init(|| Mutex::new(HashMap::new()));
+    let removed = languages.lock().unwrap().remove(&lang);
+    let Some(language) = removed else {
+        return false;
+    };
+    // The library itself is closed when the last parser or tree using it is gone.
+    drop(language);
+
+    let libraries = LANG_LIBRARIES.get_or_init(|| Mutex::new(HashMap::new()));
+    libraries
+        .lock()
+        .unwrap()
+        .retain(|_, library| library.strong_count() > 0);
+    true
+}
+
+fn language(lang: String) -> Result<Language, Error> {
+    find_language(&lang)
+}
+
+fn lang_registered(lang: String) -> bool {
+    let languages = LANG_LANGUAGES.get_or_init(|| Mutex::new(HashMap::new()));
+    languages.lock().unwrap().contains_key(&lang)
 }
 
 fn available_langs() -> Vec<String> {
@@ -59,22 +186,58 @@ fn available_langs() -> Vec<String> {
 #[magnus::init]
 fn init(ruby: &Ruby) -> Result<(), Error> {
     let namespace = ruby.define_module("TreeStump")?;
-    namespace.define_singleton_method("register_lang", function!(register_lang, 2))?;
+    namespace.define_singleton_method("register_lang", function!(register_lang, -1))?;
+    namespace.define_singleton_method("unregister_lang", function!(unregister_lang, 1))?;
+    namespace.define_singleton_method("lang_registered?", function!(lang_registered, 1))?;
     namespace.define_singleton_method("available_langs", function!(available_langs, 0))?;
+    namespace.define_singleton_method("language", function!(language, 1))?;
 
     let parser_class = namespace.define_class("Parser", ruby.class_object())?;
     parser_class.define_singleton_method("new", function!(Parser::new, 0))?;
     parser_class.define_method("set_language", method!(Parser::set_language, 1))?;
-    parser_class.define_method("parse", method!(Parser::parse, 1))?;
+    parser_class.define_method("language", method!(Parser::language, 0))?;
+    parser_class.define_method("parse", method!(Parser::parse, -1))?;
+    parser_class.define_method("parse_with", method!(Parser::parse_with, -1))?;
     parser_class.define_method("reset", method!(Parser::reset, 0))?;
     parser_class.define_method("timeout_micros", method!(Parser::timeout_micros, 0))?;
     parser_class.define_method("set_timeout_micros", method!(Parser::set_timeout_micros, 1))?;
     parser_class.define_method("build_query", method!(Parser::build_query, 1))?;
+    parser_class.define_method("cancellation_flag", method!(Parser::cancellation_flag, 0))?;
+    parser_class.define_method(
+        "set_cancellation_flag",
+        method!(Parser::set_cancellation_flag, 1),
+    )?;
+    parser_class.define_method("cancel!", method!(Parser::cancel, 0))?;
+    parser_class.define_method("included_ranges", method!(Parser::included_ranges, 0))?;
+    parser_class.define_method("included_ranges=", method!(Parser::set_included_ranges, 1))?;
+    parser_class.define_method(
+        "set_included_ranges",
+        method!(Parser::set_included_ranges, 1),
+    )?;
+    parser_class.define_method("logger", method!(Parser::logger, 0))?;
+    parser_class.define_method("logger=", method!(Parser::set_logger, 1))?;
+    parser_class.define_method("set_logger", method!(Parser::set_logger, 1))?;
+    parser_class.define_method("print_dot_graphs", method!(Parser::print_dot_graphs, 1))?;
+    parser_class.define_method(
+        "stop_printing_dot_graphs",
+        method!(Parser::stop_printing_dot_graphs, 0),
+    )?;
 
     let tree_class = namespace.define_class("Tree", ruby.class_object())?;
     tree_class.define_method("root_node", method!(Tree::root_node, 0))?;
+    tree_class.define_method(
+        "root_node_with_offset",
+        method!(Tree::root_node_with_offset, 2),
+    )?;
     tree_class.define_method("language", method!(Tree::language, 0))?;
     tree_class.define_method("walk", method!(Tree::walk, 0))?;
+    tree_class.define_method("copy", method!(Tree::copy, 0))?;
+    tree_class.define_method("edit", method!(Tree::edit, 1))?;
+    tree_class.define_method("changed_ranges", method!(Tree::changed_ranges, 1))?;
+    tree_class.define_method("source", method!(Tree::source, 0))?;
+    tree_class.define_method("text", method!(Tree::text, 0))?;
+    tree_class.define_method("byte_size", method!(Tree::byte_size, 0))?;
+    tree_class.define_method("included_ranges", method!(Tree::included_ranges, 0))?;
     tree_class.define_method("print_dot_graph", method!(Tree::print_dot_graph, 1))?;
     tree_class.define_method("inspect", method!(Tree::inspect, 0))?;
 
@@ -116,6 +279,8 @@ fn init(ruby: &Ruby) -> Result<(), Error> {
     node_class.define_method("has_changes?", method!(Node::has_changes, 0))?;
     node_class.define_method("has_error?", method!(Node::has_error, 0))?;
     node_class.define_method("is_error?", method!(Node::is_error, 0))?;
+    node_class.define_method("is_missing?", method!(Node::is_missing, 0))?;
+    node_class.define_method("missing?", method!(Node::is_missing, 0))?;
     node_class.define_method("parse_state", method!(Node::parse_state, 0))?;
     node_class.define_method("next_parse_state", method!(Node::next_parse_state, 0))?;
     node_class.define_method("start_byte", method!(Node::start_byte, 0))?;
@@ -124,6 +289,16 @@ fn init(ruby: &Ruby) -> Result<(), Error> {
     node_class.define_method("range", method!(Node::range, 0))?;
     node_class.define_method("start_position", method!(Node::start_position, 0))?;
     node_class.define_method("end_position", method!(Node::end_position, 0))?;
+    node_class.define_method(
+        "start_position_utf16",
+        method!(Node::start_position_utf16, 1),
+    )?;
+    node_class.define_method("end_position_utf16", method!(Node::end_position_utf16, 1))?;
+    node_class.define_method(
+        "start_position_chars",
+        method!(Node::start_position_chars, 1),
+    )?;
+    node_class.define_method("end_position_chars", method!(Node::end_position_chars, 1))?;
     node_class.define_method("child", method!(Node::child, 1))?;
     node_class.define_method("child_count", method!(Node::child_count, 0))?;
     node_class.define_method("named_child", method!(Node::named_child, 1))?;
@@ -134,12 +309,29 @@ fn init(ruby: &Ruby) -> Result<(), Error> {
         "field_name_for_child",
         method!(Node::field_name_for_child, 1),
     )?;
+    node_class.define_method(
+        "first_child_for_byte",
+        method!(Node::first_child_for_byte, 1),
+    )?;
+    node_class.define_method(
+        "first_named_child_for_byte",
+        method!(Node::first_named_child_for_byte, 1),
+    )?;
     node_class.define_method("parent", method!(Node::parent, 0))?;
     node_class.define_method("children", method!(Node::children, 0))?;
     node_class.define_method(
         "children_with_cursor",
         method!(Node::children_with_cursor, 1),
     )?;
+    node_class.define_method("named_children", method!(Node::named_children, 0))?;
+    node_class.define_method(
+        "children_by_field_name",
+        method!(Node::children_by_field_name, 1),
+    )?;
+    node_class.define_method(
+        "children_by_field_id",
+        method!(Node::children_by_field_id, 1),
+    )?;
     node_class.define_method(
         "named_children_with_cursor",
         method!(Node::named_children_with_cursor, 1),
@@ -180,6 +372,8 @@ fn init(ruby: &Ruby) -> Result<(), Error> {
 
     node_class.define_method("to_sexp", method!(Node::to_sexp, 0))?;
     node_class.define_method("utf8_text", method!(Node::utf8_text, 1))?;
+    node_class.define_method("byte_slice", method!(Node::byte_slice, 1))?;
+    node_class.define_method("text", method!(Node::text, 0))?;
     node_class.define_method("walk", method!(Node::walk, 0))?;
 
     node_class.define_method("inspect", method!(Node::inspect, 0))?;
@@ -195,6 +389,16 @@ fn init(ruby: &Ruby) -> Result<(), Error> {
     )?;
     point_class.define_method("row", method!(data::Point::get_row, 0))?;
     point_class.define_method("column", method!(data::Point::get_column, 0))?;
+    point_class.define_singleton_method(
+        "from_utf16_column",
+        function!(data::Point::from_utf16_column, 3),
+    )?;
+    point_class.define_singleton_method(
+        "from_char_column",
+        function!(data::Point::from_char_column, 3),
+    )?;
+    point_class.define_method("utf16_column", method!(data::Point::utf16_column, 1))?;
+    point_class.define_method("char_column", method!(data::Point::char_column, 1))?;
     point_class.define_method("inspect", method!(data::Point::inspect, 0))?;
     point_class.define_method("to_s", method!(data::Point::to_s, 0))?;
 
@@ -213,6 +417,43 @@ fn init(ruby: &Ruby) -> Result<(), Error> {
     range_class.define_method("inspect", method!(data::Range::inspect, 0))?;
     range_class.define_method("to_s", method!(data::Range::to_s, 0))?;
 
+    let input_edit_class = namespace.define_class("InputEdit", ruby.class_object())?;
+    input_edit_class.define_singleton_method("new", function!(data::InputEdit::new, 6))?;
+    input_edit_class.define_method(
+        "hash",
+        method!(<data::InputEdit as typed_data::Hash>::hash, 0),
+    )?;
+    input_edit_class.define_method(
+        "==",
+        method!(<data::InputEdit as typed_data::IsEql>::is_eql, 1),
+    )?;
+    input_edit_class.define_method(
+        "eql?",
+        method!(<data::InputEdit as typed_data::IsEql>::is_eql, 1),
+    )?;
+    input_edit_class.define_method("start_byte", method!(data::InputEdit::get_start_byte, 0))?;
+    input_edit_class.define_method(
+        "old_end_byte",
+        method!(data::InputEdit::get_old_end_byte, 0),
+    )?;
+    input_edit_class.define_method(
+        "new_end_byte",
+        method!(data::InputEdit::get_new_end_byte, 0),
+    )?;
+    input_edit_class.define_method(
+        "start_position",
+        method!(data::InputEdit::get_start_position, 0),
+    )?;
+    input_edit_class.define_method(
+        "old_end_position",
+        method!(data::InputEdit::get_old_end_position, 0),
+    )?;
+    input_edit_class.define_method(
+        "new_end_position",
+        method!(data::InputEdit::get_new_end_position, 0),
+    )?;
+    input_edit_class.define_method("inspect", method!(data::InputEdit::inspect, 0))?;
+
     let language_class = namespace.define_class("LanguageRef", ruby.class_object())?;
     language_class.define_method("version", method!(LanguageRef::version, 0))?;
     language_class.define_method("node_kind_count", method!(LanguageRef::node_kind_count, 0))?;
@@ -250,6 +491,42 @@ fn init(ruby: &Ruby) -> Result<(), Error> {
         method!(LanguageRef::lookahead_iterator, 1),
     )?;
 
+    Lazy::force(&NODE_KIND_CLASS, ruby);
+    let struct_class = Lazy::try_get_inner(&NODE_KIND_CLASS).unwrap();
+    namespace.const_set("NodeKind", struct_class)?;
+
+    let owned_language_class = namespace.define_class("Language", ruby.class_object())?;
+    owned_language_class.define_method("version", method!(Language::version, 0))?;
+    owned_language_class.define_method("node_kind_count", method!(Language::node_kind_count, 0))?;
+    owned_language_class
+        .define_method("parse_state_count", method!(Language::parse_state_count, 0))?;
+    owned_language_class
+        .define_method("node_kind_for_id", method!(Language::node_kind_for_id, 1))?;
+    owned_language_class
+        .define_method("id_for_node_kind", method!(Language::id_for_node_kind, 2))?;
+    owned_language_class.define_method(
+        "node_kind_is_named",
+        method!(Language::node_kind_is_named, 1),
+    )?;
+    owned_language_class.define_method(
+        "node_kind_is_visible",
+        method!(Language::node_kind_is_visible, 1),
+    )?;
+    owned_language_class.define_method("field_count", method!(Language::field_count, 0))?;
+    owned_language_class
+        .define_method("field_name_for_id", method!(Language::field_name_for_id, 1))?;
+    owned_language_class
+        .define_method("field_id_for_name", method!(Language::field_id_for_name, 1))?;
+    owned_language_class.define_method("next_state", method!(Language::next_state, 2))?;
+    owned_language_class.define_method(
+        "lookahead_iterator",
+        method!(Language::lookahead_iterator, 1),
+    )?;
+    owned_language_class.define_method("node_kinds", method!(Language::node_kinds, 0))?;
+    owned_language_class.define_method("fields", method!(Language::fields, 0))?;
+    owned_language_class.define_method("supertypes", method!(Language::supertypes, 0))?;
+    owned_language_class.define_method("inspect", method!(Language::inspect, 0))?;
+
     let lookahead_iterator_class =
         namespace.define_class("LookaheadIterator", ruby.class_object())?;
     lookahead_iterator_class.define_method("next", method!(LookaheadIterator::next, 0))?;
@@ -280,15 +557,41 @@ fn init(ruby: &Ruby) -> Result<(), Error> {
         "is_pattern_guaranteed_at_step",
         method!(Query::is_pattern_guaranteed_at_step, 1),
     )?;
+    query_class.define_method("general_predicates", method!(Query::general_predicates, 1))?;
+    query_class.define_method("define_predicate", method!(Query::define_predicate, 1))?;
+    query_class.define_method(
+        "ignore_unknown_predicates",
+        method!(Query::ignore_unknown_predicates, 0),
+    )?;
+    query_class.define_method(
+        "set_ignore_unknown_predicates",
+        method!(Query::set_ignore_unknown_predicates, 1),
+    )?;
+    query_class.define_method("property_settings", method!(Query::property_settings, 1))?;
+    query_class.define_method(
+        "property_predicates",
+        method!(Query::property_predicates, 1),
+    )?;
 
     Lazy::force(&QUERY_CAPTURE_CLASS, ruby);
     let struct_class = Lazy::try_get_inner(&QUERY_CAPTURE_CLASS).unwrap();
     namespace.const_set("QueryCapture", struct_class)?;
 
+    Lazy::force(&QUERY_PREDICATE_CLASS, ruby);
+    let struct_class = Lazy::try_get_inner(&QUERY_PREDICATE_CLASS).unwrap();
+    namespace.const_set("QueryPredicate", struct_class)?;
+
+    Lazy::force(&QUERY_PROPERTY_CLASS, ruby);
+    let struct_class = Lazy::try_get_inner(&QUERY_PROPERTY_CLASS).unwrap();
+    namespace.const_set("QueryProperty", struct_class)?;
+
     let query_match_class = namespace.define_class("QueryMatch", ruby.class_object())?;
     query_match_class.define_method("pattern_index", method!(QueryMatch::pattern_index, 0))?;
     query_match_class.define_method("captures", method!(QueryMatch::captures, 0))?;
 
+    // Holds the state of a running `QueryCursor#matches` or `#captures`.
+    namespace.define_class("QueryMatches", ruby.class_object())?;
+
     let query_cursor_class = namespace.define_class("QueryCursor", ruby.class_object())?;
     query_cursor_class.define_singleton_method("new", function!(QueryCursor::new, 0))?;
     query_cursor_class.define_method("match_limit", method!(QueryCursor::match_limit, 0))?;
@@ -298,7 +601,8 @@ fn init(ruby: &Ruby) -> Result<(), Error> {
         "did_exceed_match_limit",
         method!(QueryCursor::did_exceed_match_limit, 0),
     )?;
-    query_cursor_class.define_method("matches", method!(QueryCursor::matches, 3))?;
+    query_cursor_class.define_method("matches", method!(QueryCursor::matches, -1))?;
+    query_cursor_class.define_method("captures", method!(QueryCursor::captures, -1))?;
     query_cursor_class.define_method("set_byte_range", method!(QueryCursor::set_byte_range, 1))?;
     query_cursor_class
         .define_method("set_point_range", method!(QueryCursor::set_point_range, 1))?;
@@ -307,5 +611,25 @@ fn init(ruby: &Ruby) -> Result<(), Error> {
         method!(QueryCursor::set_max_start_depth, 1),
     )?;
 
+    Lazy::force(&HIGHLIGHT_EVENT_CLASS, ruby);
+    let struct_class = Lazy::try_get_inner(&HIGHLIGHT_EVENT_CLASS).unwrap();
+    namespace.const_set("HighlightEvent", struct_class)?;
+
+    let highlighter_class = namespace.define_class("Highlighter", ruby.class_object())?;
+    highlighter_class.define_singleton_method("new", function!(Highlighter::new, -1))?;
+    highlighter_class.define_method("language_name", method!(Highlighter::language_name, 0))?;
+    highlighter_class.define_method("highlight_names", method!(Highlighter::highlight_names, 0))?;
+    highlighter_class.define_method("add_injection", method!(Highlighter::add_injection, -1))?;
+    highlighter_class.define_method("highlight", method!(Highlighter::highlight, 1))?;
+
+    Lazy::force(&TAG_CLASS, ruby);
+    let struct_class = Lazy::try_get_inner(&TAG_CLASS).unwrap();
+    namespace.const_set("Tag", struct_class)?;
+
+    let tagger_class = namespace.define_class("Tagger", ruby.class_object())?;
+    tagger_class.define_singleton_method("new", function!(Tagger::new, -1))?;
+    tagger_class.define_method("language_name", method!(Tagger::language_name, 0))?;
+    tagger_class.define_method("tags", method!(Tagger::tags, 1))?;
+
     Ok(())
 }
@@ -1,67 +1,411 @@
+use magnus::{
+    block::Proc,
+    gc::Marker,
+    scan_args::{get_kwargs, scan_args},
+    typed_data,
+    value::Opaque,
+    DataTypeFunctions, RArray, RFile, RHash, RString, Ruby, Symbol, TryConvert, TypedData, Value,
+};
+use tree_sitter::LogType;
+
+use crate::data::{self, Point};
+use crate::find_language;
+use crate::language::Language;
 use crate::query::Query;
-use crate::tree::Tree;
-use crate::util::build_error;
-use crate::LANG_LANGUAGES;
+use crate::tree::{SharedTree, SourceText, Tree};
+use crate::util::{
+    build_error, build_parse_cancelled_error, build_parse_timeout_error, try_borrow,
+    try_borrow_mut, without_gvl_cancellable, INTERRUPT_FLAG,
+};
 
 use std::cell::RefCell;
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+enum ParseInput {
+    Utf8(SourceText),
+    // Byte offsets and columns of a UTF-16 tree count UTF-16 bytes (2 per code unit).
+    Utf16(Vec<u16>),
+}
 
-#[magnus::wrap(class = "TreeStump::Parser")]
+#[derive(TypedData)]
+#[magnus(class = "TreeStump::Parser", mark)]
 pub struct Parser {
     raw_parser: RefCell<tree_sitter::Parser>,
+    // Dropped after `raw_parser`, which still calls into the grammar library.
+    language: RefCell<Option<Language>>,
+    // Shared with the raw parser. It lives outside the `RefCell` so that other
+    // threads can cancel a running parse.
+    cancellation_flag: Arc<AtomicUsize>,
+    logger: RefCell<Option<Opaque<Proc>>>,
+}
+
+impl DataTypeFunctions for Parser {
+    fn mark(&self, marker: &Marker) {
+        if let Some(logger) = *self.logger.borrow() {
+            marker.mark(logger);
+        }
+    }
 }
 
 impl Parser {
     pub fn new() -> Self {
+        let cancellation_flag = Arc::new(AtomicUsize::new(0));
+        let mut raw_parser = tree_sitter::Parser::new();
+        // SAFETY: the flag is kept alive by `Parser` and dropped after `raw_parser`.
+        unsafe { raw_parser.set_cancellation_flag(Some(&cancellation_flag)) };
+
         Self {
-            raw_parser: RefCell::new(tree_sitter::Parser::new()),
+            raw_parser: RefCell::new(raw_parser),
+            language: RefCell::new(None),
+            cancellation_flag,
+            logger: RefCell::new(None),
         }
     }
 
-    pub fn set_language(&self, lang: String) -> Result<bool, magnus::Error> {
-        let languages = LANG_LANGUAGES.get_or_init(|| Mutex::new(HashMap::new()));
-        let languages = languages.lock().unwrap();
-        let language = languages.get(&lang);
-        match language {
-            Some(language) => {
-                let result = self.raw_parser.borrow_mut().set_language(language);
-                result.map_or_else(|e| Err(build_error(e.to_string())), |_| Ok(true))
+    // Accepts a registered language name or a `TreeStump::Language`.
+    pub fn set_language(&self, lang: Value) -> Result<bool, magnus::Error> {
+        let language = match <&Language>::try_convert(lang) {
+            Ok(language) => language.clone(),
+            Err(_) => find_language(&String::try_convert(lang)?)?,
+        };
+        try_borrow_mut(&self.raw_parser, "Parser")?
+            .set_language(&language.raw_language)
+            .map_err(|e| build_error(e.to_string()))?;
+        *try_borrow_mut(&self.language, "Parser")? = Some(language);
+        Ok(true)
+    }
+
+    pub fn parse(ruby: &Ruby, rb_self: &Self, args: &[Value]) -> Result<Tree, magnus::Error> {
+        let args =
+            scan_args::<(RString,), (Option<Option<typed_data::Obj<Tree>>>,), (), (), RHash, ()>(
+                args,
+            )?;
+        let (source,) = args.required;
+        let (old_tree,) = args.optional;
+        let kwargs = get_kwargs::<_, (), (Option<Symbol>, Option<bool>), ()>(
+            args.keywords,
+            &[],
+            &["encoding", "keep_source"],
+        )?;
+        let (encoding, keep_source) = kwargs.optional;
+        let old_tree = old_tree.flatten().map(|tree| tree.get_raw_tree());
+        let old_tree = old_tree.as_deref().map(|tree| &**tree);
+
+        let encoding = encoding.as_ref().map(|e| e.name()).transpose()?;
+        let input = match encoding.as_deref() {
+            // A UTF-8 parse uses the bytes as given, so offsets match them
+            // whatever the String's encoding is.
+            None | Some("utf8") => ParseInput::Utf8(SourceText::new(source)),
+            Some("utf16") => ParseInput::Utf16(source.to_string()?.encode_utf16().collect()),
+            Some(name) => {
+                return Err(magnus::Error::new(
+                    ruby.exception_arg_error(),
+                    format!("Unsupported encoding :{}. Expected :utf8 or :utf16", name),
+                ))
             }
-            None => Err(build_error(format!("Language {} is not registered", lang))),
+        };
+        let parse = |raw_parser: &mut tree_sitter::Parser| match &input {
+            ParseInput::Utf8(source) => raw_parser.parse(source.as_bytes(), old_tree),
+            ParseInput::Utf16(source) => raw_parser.parse_utf16(source, old_tree),
+        };
+        ruby.thread_check_ints()?;
+
+        // Parsing runs without the GVL; the borrow keeps other threads from
+        // using this parser meanwhile, while `cancel!` only needs the flag.
+        let mut raw_parser = try_borrow_mut(&rb_self.raw_parser, "Parser")?;
+        let logger = *try_borrow(&rb_self.logger, "Parser")?;
+        // Only once the parser is ours, so that a call rejected as concurrent
+        // leaves the cancellation to the parse it was meant for.
+        rb_self.check_cancelled()?;
+        let tree = match logger {
+            // The logger is Ruby code, so the GVL is kept.
+            Some(logger) => with_logger(
+                ruby,
+                &mut *raw_parser,
+                logger,
+                &rb_self.cancellation_flag,
+                parse,
+            )?,
+            None => loop {
+                let tree =
+                    without_gvl_cancellable(|| parse(&mut *raw_parser), &rb_self.cancellation_flag);
+                if tree.is_some() || !rb_self.take_interrupt() {
+                    break tree;
+                }
+                // Interrupts that raise nothing (a trap handler, `Thread#wakeup`, ...)
+                // only pause the parse, which tree-sitter resumes on the next call.
+                if let Err(e) = ruby.thread_check_ints() {
+                    raw_parser.reset();
+                    return Err(e);
+                }
+            },
+        };
+        drop(raw_parser);
+        // Node#text and source-less queries need the text the offsets refer to,
+        // which only a UTF-8 parse keeps as is. Callers that always pass the
+        // source can drop it with `keep_source: false` to save memory.
+        let (source, utf16) = match input {
+            ParseInput::Utf8(source) => (keep_source.unwrap_or(true).then_some(source), false),
+            ParseInput::Utf16(_) => (None, true),
+        };
+        rb_self.build_tree(ruby, tree, source, utf16)
+    }
+
+    // The block receives `(byte_offset, point)` and returns the text at that
+    // position, or nil/"" at the end of the input. As the block is Ruby code,
+    // this keeps the GVL while parsing.
+    pub fn parse_with(ruby: &Ruby, rb_self: &Self, args: &[Value]) -> Result<Tree, magnus::Error> {
+        let args =
+            scan_args::<(), (Option<Option<typed_data::Obj<Tree>>>,), (), (), (), Proc>(args)?;
+        let (old_tree,) = args.optional;
+        let old_tree = old_tree.flatten().map(|tree| tree.get_raw_tree());
+        let block = args.block;
+
+        // Ruby errors cannot cross tree-sitter's callback, so the input is
+        // ended early and the error is raised after parsing stops.
+        let mut error = None;
+        let parse = |raw_parser: &mut tree_sitter::Parser| {
+            raw_parser.parse_with(
+                &mut |byte_offset, point| {
+                    if error.is_some() {
+                        return Vec::new();
+                    }
+                    match block.call::<_, Option<RString>>((byte_offset, Point::from(point))) {
+                        // SAFETY: the bytes are copied before Ruby can run again.
+                        Ok(chunk) => {
+                            chunk.map_or_else(Vec::new, |c| unsafe { c.as_slice() }.to_vec())
+                        }
+                        Err(e) => {
+                            error = Some(e);
+                            Vec::new()
+                        }
+                    }
+                },
+                old_tree.as_deref().map(|tree| &**tree),
+            )
+        };
+
+        let mut raw_parser = try_borrow_mut(&rb_self.raw_parser, "Parser")?;
+        let logger = *try_borrow(&rb_self.logger, "Parser")?;
+        rb_self.check_cancelled()?;
+        let tree = match logger {
+            Some(logger) => with_logger(
+                ruby,
+                &mut *raw_parser,
+                logger,
+                &rb_self.cancellation_flag,
+                parse,
+            )?,
+            None => parse(&mut *raw_parser),
+        };
+
+        if let Some(e) = error {
+            raw_parser.reset();
+            return Err(e);
         }
+        drop(raw_parser);
+        rb_self.build_tree(ruby, tree, None, false)
     }
 
-    pub fn parse(&self, source: String) -> Result<Tree, magnus::Error> {
-        let tree = self.raw_parser.borrow_mut().parse(source, None);
+    fn build_tree(
+        &self,
+        ruby: &Ruby,
+        tree: Option<tree_sitter::Tree>,
+        source: Option<SourceText>,
+        utf16: bool,
+    ) -> Result<Tree, magnus::Error> {
+        let Some(tree) = tree else {
+            // tree-sitter would resume the stopped parse on the next call.
+            let mut raw_parser = try_borrow_mut(&self.raw_parser, "Parser")?;
+            raw_parser.reset();
+            let timeout = raw_parser.timeout_micros();
+            drop(raw_parser);
+
+            let cancelled = self.cancellation_flag.swap(0, Ordering::SeqCst) & !INTERRUPT_FLAG != 0;
+            // Raises the pending interrupt if the parse was stopped by one.
+            ruby.thread_check_ints()?;
+            if cancelled {
+                return Err(build_parse_cancelled_error("Parsing was cancelled"));
+            }
+            if try_borrow(&self.language, "Parser")?.is_none() {
+                return Err(build_error(
+                    "The parser has no language, set one with set_language",
+                ));
+            }
+            if timeout > 0 {
+                return Err(build_parse_timeout_error(format!(
+                    "Parsing exceeded the timeout of {} microseconds",
+                    timeout
+                )));
+            }
+            return Err(build_error("Failed to parse"));
+        };
+
+        // A cancellation that came too late to stop this parse must not stop
+        // the next one.
+        self.cancellation_flag.store(0, Ordering::SeqCst);
+        let library = try_borrow(&self.language, "Parser")?
+            .as_ref()
+            .map(|l| Arc::clone(&l.library));
+        Ok(Tree::from(Arc::new(SharedTree::new(
+            tree, library, source, utf16,
+        ))))
+    }
 
-        match tree {
-            Some(tree) => Ok(Tree::from(Arc::new(tree))),
-            None => Err(build_error("Failed to parse")),
+    // A cancellation requested while no parse was running stops the next one
+    // right away, however short it would be.
+    fn check_cancelled(&self) -> Result<(), magnus::Error> {
+        if self.cancellation_flag.swap(0, Ordering::SeqCst) != 0 {
+            return Err(build_parse_cancelled_error("Parsing was cancelled"));
         }
+        Ok(())
+    }
+
+    // Clears an interrupt noted while parsing without the GVL, and returns
+    // whether it was the only reason the parse stopped.
+    fn take_interrupt(&self) -> bool {
+        self.cancellation_flag
+            .fetch_and(!INTERRUPT_FLAG, Ordering::SeqCst)
+            == INTERRUPT_FLAG
     }
 
-    pub fn reset(&self) {
-        self.raw_parser.borrow_mut().reset();
+    pub fn cancellation_flag(&self) -> usize {
+        self.cancellation_flag.load(Ordering::SeqCst)
     }
 
-    pub fn timeout_micros(&self) -> u64 {
-        self.raw_parser.borrow().timeout_micros()
+    pub fn set_cancellation_flag(&self, value: usize) {
+        self.cancellation_flag.store(value, Ordering::SeqCst);
     }
 
-    pub fn set_timeout_micros(&self, timeout: u64) {
-        self.raw_parser.borrow_mut().set_timeout_micros(timeout);
+    // Stops the running parse, or the next one if none is running.
+    pub fn cancel(&self) {
+        self.set_cancellation_flag(1);
     }
 
-    fn language(&self) -> Option<tree_sitter::Language> {
-        self.raw_parser.borrow().language()
+    pub fn reset(&self) -> Result<(), magnus::Error> {
+        try_borrow_mut(&self.raw_parser, "Parser")?.reset();
+        Ok(())
+    }
+
+    pub fn timeout_micros(&self) -> Result<u64, magnus::Error> {
+        Ok(try_borrow(&self.raw_parser, "Parser")?.timeout_micros())
+    }
+
+    pub fn set_timeout_micros(&self, timeout: u64) -> Result<(), magnus::Error> {
+        try_borrow_mut(&self.raw_parser, "Parser")?.set_timeout_micros(timeout);
+        Ok(())
+    }
+
+    pub fn language(&self) -> Result<Option<Language>, magnus::Error> {
+        Ok(try_borrow(&self.language, "Parser")?.clone())
+    }
+
+    // Ranges must be sorted and must not overlap. An empty array includes the
+    // whole document again.
+    pub fn set_included_ranges(&self, ranges: RArray) -> Result<(), magnus::Error> {
+        let ranges = ranges
+            .into_iter()
+            .map(|range| <&data::Range>::try_convert(range).map(|range| range.into_raw()))
+            .collect::<Result<Vec<_>, _>>()?;
+        try_borrow_mut(&self.raw_parser, "Parser")?
+            .set_included_ranges(&ranges)
+            .map_err(|e| {
+                let range = data::Range::from(ranges[e.0]);
+                if range.end_byte < range.start_byte {
+                    build_error(format!(
+                        "Included range at index {} ends before it starts: {}",
+                        e.0,
+                        range.inspect()
+                    ))
+                } else {
+                    build_error(format!(
+                        "Included range at index {} overlaps or precedes the previous range: {}. \
+                         Ranges must be sorted and must not overlap",
+                        e.0,
+                        range.inspect()
+                    ))
+                }
+            })
+    }
+
+    pub fn included_ranges(&self) -> Result<Vec<data::Range>, magnus::Error> {
+        let raw_parser = try_borrow(&self.raw_parser, "Parser")?;
+        Ok(raw_parser
+            .included_ranges()
+            .into_iter()
+            .map(data::Range::from)
+            .collect())
+    }
+
+    pub fn logger(&self) -> Result<Option<Proc>, magnus::Error> {
+        let ruby = Ruby::get().expect("Ruby is not initialized");
+        Ok(try_borrow(&self.logger, "Parser")?.map(|logger| ruby.get_inner(logger)))
+    }
+
+    // The proc receives `(type, message)` with type being `:parse` or `:lex`.
+    // Pass nil to stop logging.
+    pub fn set_logger(&self, logger: Option<Proc>) -> Result<(), magnus::Error> {
+        *try_borrow_mut(&self.logger, "Parser")? = logger.map(Opaque::from);
+        Ok(())
+    }
+
+    pub fn print_dot_graphs(&self, io: RFile) -> Result<(), magnus::Error> {
+        try_borrow_mut(&self.raw_parser, "Parser")?.print_dot_graphs(&io);
+        Ok(())
+    }
+
+    pub fn stop_printing_dot_graphs(&self) -> Result<(), magnus::Error> {
+        try_borrow_mut(&self.raw_parser, "Parser")?.stop_printing_dot_graphs();
+        Ok(())
     }
 
     pub fn build_query(&self, source: String) -> Result<Query, magnus::Error> {
-        let lang = self.language();
-        lang.map_or_else(
+        let lang = try_borrow(&self.language, "Parser")?;
+        lang.as_ref().map_or_else(
             || Err(build_error("Failed to get language from parser")),
-            |lang| Query::new(&lang, source),
+            |lang| Query::new(lang, source),
         )
     }
 }
+
+// Installs the logger for a single parse. Ruby errors cannot cross
+// tree-sitter's callback, so once the logger raises it is skipped, the parse
+// is stopped through the cancellation flag and the error is raised afterwards.
+fn with_logger<R>(
+    ruby: &Ruby,
+    raw_parser: &mut tree_sitter::Parser,
+    logger: Opaque<Proc>,
+    cancellation_flag: &AtomicUsize,
+    func: impl FnOnce(&mut tree_sitter::Parser) -> R,
+) -> Result<R, magnus::Error> {
+    let logger = ruby.get_inner(logger);
+    let error = RefCell::new(None);
+    raw_parser.set_logger(Some(Box::new(|log_type, message| {
+        if error.borrow().is_some() {
+            return;
+        }
+        let log_type = match log_type {
+            LogType::Parse => "parse",
+            LogType::Lex => "lex",
+        };
+        if let Err(e) = logger.call::<_, Value>((ruby.to_symbol(log_type), message)) {
+            *error.borrow_mut() = Some(e);
+            cancellation_flag.fetch_or(INTERRUPT_FLAG, Ordering::SeqCst);
+        }
+    })));
+    // The logger borrows `error`, so it must be removed even on a panic.
+    let result = panic::catch_unwind(AssertUnwindSafe(|| func(raw_parser)));
+    raw_parser.set_logger(None);
+    let result = result.unwrap_or_else(|e| panic::resume_unwind(e));
+
+    match error.into_inner() {
+        Some(e) => {
+            cancellation_flag.fetch_and(!INTERRUPT_FLAG, Ordering::SeqCst);
+            raw_parser.reset();
+            Err(e)
+        }
+        None => Ok(result),
+    }
+}
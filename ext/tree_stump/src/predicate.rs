@@ -0,0 +1,136 @@
+use magnus::{gc::Marker, r_regexp::Opts, value::Opaque, RRegexp, Ruby};
+use tree_sitter::QueryPredicateArg;
+
+use crate::util::build_query_error;
+
+// tree-sitter compiles `#match?` patterns with the `regex` crate, whose syntax
+// differs from Ruby's (e.g. `^` only anchors at the start of the text). The
+// query source is rewritten so that tree-sitter reports these operators as
+// general predicates and they can be evaluated with Ruby's `Regexp` instead.
+// Replacement names keep the same length so byte offsets stay valid.
+const REGEX_OPERATORS: [(&str, &str); 4] = [
+    ("match?", "match!"),
+    ("not-match?", "not-match!"),
+    ("any-match?", "any-match!"),
+    ("any-not-match?", "any-not-match!"),
+];
+
+pub fn rewrite_regex_predicates(source: &str) -> String {
+    let bytes = source.as_bytes();
+    let mut result = String::with_capacity(source.len());
+    let mut last = 0;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'"' => {
+                i += 1;
+                while i < bytes.len() && bytes[i] != b'"' {
+                    i += if bytes[i] == b'\\' { 2 } else { 1 };
+                }
+                i += 1;
+            }
+            b';' => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+            }
+            b'#' => {
+                let start = i + 1;
+                let mut end = start;
+                while end < bytes.len()
+                    && !bytes[end].is_ascii_whitespace()
+                    && !matches!(bytes[end], b'(' | b')' | b'"')
+                {
+                    end += 1;
+                }
+                let name = &source[start..end];
+                if let Some((_, renamed)) = REGEX_OPERATORS.iter().find(|(op, _)| *op == name) {
+                    result.push_str(&source[last..start]);
+                    result.push_str(renamed);
+                    last = end;
+                }
+                i = end;
+            }
+            _ => i += 1,
+        }
+    }
+
+    result.push_str(&source[last..]);
+    result
+}
+
+pub fn is_regex_operator(operator: &str) -> bool {
+    REGEX_OPERATORS
+        .iter()
+        .any(|(_, renamed)| *renamed == operator)
+}
+
+pub fn original_operator(operator: &str) -> &str {
+    REGEX_OPERATORS
+        .iter()
+        .find(|(_, renamed)| *renamed == operator)
+        .map_or(operator, |(op, _)| op)
+}
+
+pub struct RegexPredicate {
+    pub capture_index: u32,
+    pub regex: Opaque<RRegexp>,
+    pub is_positive: bool,
+    pub match_all: bool,
+}
+
+impl RegexPredicate {
+    pub fn new(
+        ruby: &Ruby,
+        predicate: &tree_sitter::QueryPredicate,
+    ) -> Result<Self, magnus::Error> {
+        let operator = original_operator(&predicate.operator);
+        let (capture_index, pattern) = match predicate.args.as_ref() {
+            [QueryPredicateArg::Capture(index), QueryPredicateArg::String(pattern)] => {
+                (*index, pattern)
+            }
+            _ => {
+                return Err(build_query_error(format!(
+                    "#{} predicate expects a capture and a regular expression literal",
+                    operator
+                )))
+            }
+        };
+        let regex = ruby
+            .reg_new(pattern, Opts::new())
+            .map_err(|e| build_query_error(format!("Invalid regex '{}': {}", pattern, e)))?;
+
+        Ok(Self {
+            capture_index,
+            regex: Opaque::from(regex),
+            is_positive: !operator.contains("not-"),
+            match_all: !operator.starts_with("any-"),
+        })
+    }
+
+    // Mirrors how tree-sitter evaluates its own text predicates.
+    pub fn is_satisfied(
+        &self,
+        ruby: &Ruby,
+        captures: &[tree_sitter::QueryCapture],
+        source: &[u8],
+    ) -> Result<bool, magnus::Error> {
+        let regex = ruby.get_inner(self.regex);
+        for capture in captures.iter().filter(|c| c.index == self.capture_index) {
+            let text = source.get(capture.node.byte_range()).unwrap_or_default();
+            let is_match = regex.reg_match(&*String::from_utf8_lossy(text))?.is_some();
+            if is_match != self.is_positive && self.match_all {
+                return Ok(false);
+            }
+            if is_match == self.is_positive && !self.match_all {
+                return Ok(true);
+            }
+        }
+        Ok(true)
+    }
+
+    pub fn mark(&self, marker: &Marker) {
+        marker.mark(self.regex);
+    }
+}
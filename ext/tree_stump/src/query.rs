@@ -1,30 +1,169 @@
-use std::{cell::RefCell, sync::Arc};
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, VecDeque},
+    ops::Range,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
 use magnus::{
-    block::Yield,
+    block::Proc,
+    gc::Marker,
+    scan_args::scan_args,
     symbol::IntoSymbol,
     typed_data,
     value::{InnerRef, Opaque, ReprValue},
-    Class, Error, IntoValue, RArray, RStruct, RTypedData, Ruby, Value,
+    Class, DataTypeFunctions, Error, RArray, RString, RStruct, RTypedData, Ruby, TypedData, Value,
 };
 
-use crate::{data::Point, tree::Node, util::build_error, QUERY_CAPTURE_CLASS};
+use crate::{
+    data::Point,
+    language::Language,
+    predicate::{self, RegexPredicate},
+    tree::{Node, SharedTree, SourceText},
+    util::{build_error, build_query_error, try_borrow, try_borrow_mut, without_gvl},
+    QUERY_CAPTURE_CLASS, QUERY_PREDICATE_CLASS, QUERY_PROPERTY_CLASS,
+};
 
-#[magnus::wrap(class = "TreeStump::Query", free_immediately)]
-#[derive(Debug)]
+#[derive(TypedData)]
+#[magnus(class = "TreeStump::Query", free_immediately, mark)]
 pub struct Query {
     pub raw_query: RefCell<tree_sitter::Query>,
+    regex_predicates: Vec<Vec<RegexPredicate>>,
+    custom_predicates: RefCell<HashMap<String, Opaque<Proc>>>,
+    ignore_unknown_predicates: Cell<bool>,
+    // Runs of `QueryCursor#matches`/`#captures` that still read the query.
+    // Shared with them, as a run can be freed by the GC after the query.
+    running_matches: Arc<AtomicUsize>,
+    _language: Language,
+}
+
+impl DataTypeFunctions for Query {
+    fn mark(&self, marker: &Marker) {
+        for predicates in &self.regex_predicates {
+            for predicate in predicates {
+                predicate.mark(marker);
+            }
+        }
+        for callback in self.custom_predicates.borrow().values() {
+            marker.mark(*callback);
+        }
+    }
 }
 
 impl Query {
-    pub fn new(language: &tree_sitter::Language, source: String) -> Result<Self, magnus::Error> {
-        let raw_query = tree_sitter::Query::new(language, source.as_str());
-        let raw_query = raw_query.map_err(|e| build_error(e.to_string()));
-        raw_query.map(|q| Self {
-            raw_query: RefCell::new(q),
+    pub fn new(language: &Language, source: String) -> Result<Self, magnus::Error> {
+        let ruby = Ruby::get().expect("Ruby is not initialized");
+        let source = predicate::rewrite_regex_predicates(&source);
+        let raw_query = tree_sitter::Query::new(&language.raw_language, source.as_str())
+            .map_err(|e| build_query_error(e.to_string()))?;
+
+        let regex_predicates = (0..raw_query.pattern_count())
+            .map(|index| {
+                raw_query
+                    .general_predicates(index)
+                    .iter()
+                    .filter(|p| predicate::is_regex_operator(&p.operator))
+                    .map(|p| RegexPredicate::new(&ruby, p))
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            raw_query: RefCell::new(raw_query),
+            regex_predicates,
+            custom_predicates: RefCell::new(HashMap::new()),
+            ignore_unknown_predicates: Cell::new(false),
+            running_matches: Arc::new(AtomicUsize::new(0)),
+            _language: language.clone(),
         })
     }
 
+    fn satisfies_predicates(
+        &self,
+        ruby: &Ruby,
+        query_match: &CollectedMatch,
+        raw_tree: &Arc<SharedTree>,
+        source: impl Fn() -> RString,
+        text: &[u8],
+    ) -> Result<bool, Error> {
+        for predicate in &self.regex_predicates[query_match.pattern_index] {
+            if !predicate.is_satisfied(ruby, &query_match.captures, text)? {
+                return Ok(false);
+            }
+        }
+
+        let raw_query = self.raw_query.borrow();
+        for p in raw_query
+            .general_predicates(query_match.pattern_index)
+            .iter()
+            .filter(|p| !predicate::is_regex_operator(&p.operator))
+        {
+            let callback = self.custom_predicates.borrow().get(&*p.operator).copied();
+            let callback = match callback {
+                Some(callback) => ruby.get_inner(callback),
+                None if self.ignore_unknown_predicates.get() => continue,
+                None => {
+                    return Err(build_query_error(format!(
+                        "Unknown predicate #{}",
+                        p.operator
+                    )))
+                }
+            };
+
+            let capture_node = |index: u32| {
+                query_match
+                    .nodes_for_capture_index(index)
+                    .next()
+                    .map(|node| Node::new(Arc::clone(raw_tree), node))
+            };
+            let (node, rest) = match p.args.split_first() {
+                Some((tree_sitter::QueryPredicateArg::Capture(index), rest)) => {
+                    (capture_node(*index), rest)
+                }
+                _ => (None, &p.args[..]),
+            };
+            let args = ruby.ary_new_capa(rest.len());
+            for arg in rest {
+                match arg {
+                    tree_sitter::QueryPredicateArg::Capture(index) => {
+                        args.push(capture_node(*index))?
+                    }
+                    tree_sitter::QueryPredicateArg::String(value) => args.push(&**value)?,
+                }
+            }
+
+            let result: Value = callback.call((node, args, source()))?;
+            if !result.to_bool() {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    pub fn define_predicate(
+        ruby: &Ruby,
+        rb_self: typed_data::Obj<Self>,
+        name: String,
+    ) -> Result<typed_data::Obj<Self>, Error> {
+        let callback = ruby.block_proc()?;
+        rb_self
+            .custom_predicates
+            .borrow_mut()
+            .insert(name, Opaque::from(callback));
+        Ok(rb_self)
+    }
+
+    pub fn ignore_unknown_predicates(&self) -> bool {
+        self.ignore_unknown_predicates.get()
+    }
+
+    pub fn set_ignore_unknown_predicates(&self, ignore: bool) {
+        self.ignore_unknown_predicates.set(ignore);
+    }
+
     pub fn start_byte_for_pattern(&self, pattern_index: usize) -> usize {
         self.raw_query
             .borrow()
@@ -61,12 +200,26 @@ impl Query {
             .capture_index_for_name(name.as_str())
     }
 
-    pub fn disable_capture(&self, name: String) {
-        self.raw_query.borrow_mut().disable_capture(&name);
+    // Running matches hold on to the query, so it cannot change under them.
+    fn check_not_running(&self) -> Result<(), Error> {
+        if self.running_matches.load(Ordering::SeqCst) > 0 {
+            return Err(build_error(
+                "Query is in use by a running QueryCursor#matches or #captures",
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn disable_capture(&self, name: String) -> Result<(), Error> {
+        self.check_not_running()?;
+        try_borrow_mut(&self.raw_query, "Query")?.disable_capture(&name);
+        Ok(())
     }
 
-    pub fn disable_pattern(&self, index: usize) {
-        self.raw_query.borrow_mut().disable_pattern(index);
+    pub fn disable_pattern(&self, index: usize) -> Result<(), Error> {
+        self.check_not_running()?;
+        try_borrow_mut(&self.raw_query, "Query")?.disable_pattern(index);
+        Ok(())
     }
 
     pub fn is_pattern_rooted(&self, index: usize) -> bool {
@@ -76,15 +229,115 @@ impl Query {
     pub fn is_pattern_guaranteed_at_step(&self, index: usize) -> bool {
         self.raw_query.borrow().is_pattern_guaranteed_at_step(index)
     }
+
+    pub fn general_predicates(
+        ruby: &Ruby,
+        rb_self: typed_data::Obj<Self>,
+        index: usize,
+    ) -> Result<RArray, Error> {
+        let raw_query = rb_self.raw_query.borrow();
+        check_pattern_index(&raw_query, index)?;
+        let struct_class = QUERY_PREDICATE_CLASS.get_inner_ref_with(ruby);
+        let array = ruby.ary_new();
+        for p in raw_query
+            .general_predicates(index)
+            .iter()
+            .filter(|p| !predicate::is_regex_operator(&p.operator))
+        {
+            let args = ruby.ary_new_capa(p.args.len());
+            for arg in p.args.iter() {
+                match arg {
+                    tree_sitter::QueryPredicateArg::Capture(index) => args.push(*index)?,
+                    tree_sitter::QueryPredicateArg::String(value) => args.push(&**value)?,
+                }
+            }
+            array.push(struct_class.new_instance((&*p.operator, args))?)?;
+        }
+        Ok(array)
+    }
+
+    pub fn property_settings(
+        ruby: &Ruby,
+        rb_self: typed_data::Obj<Self>,
+        index: usize,
+    ) -> Result<RArray, Error> {
+        let raw_query = rb_self.raw_query.borrow();
+        check_pattern_index(&raw_query, index)?;
+        let array = ruby.ary_new();
+        for property in raw_query.property_settings(index) {
+            array.push(build_property(ruby, property)?)?;
+        }
+        Ok(array)
+    }
+
+    pub fn property_predicates(
+        ruby: &Ruby,
+        rb_self: typed_data::Obj<Self>,
+        index: usize,
+    ) -> Result<RArray, Error> {
+        let raw_query = rb_self.raw_query.borrow();
+        check_pattern_index(&raw_query, index)?;
+        let array = ruby.ary_new();
+        for (property, is_positive) in raw_query.property_predicates(index) {
+            array.push((build_property(ruby, property)?, *is_positive))?;
+        }
+        Ok(array)
+    }
+}
+
+fn check_pattern_index(raw_query: &tree_sitter::Query, index: usize) -> Result<(), Error> {
+    if index >= raw_query.pattern_count() {
+        return Err(build_error(format!(
+            "Pattern index {} is out of range (pattern count: {})",
+            index,
+            raw_query.pattern_count()
+        )));
+    }
+    Ok(())
 }
 
-#[magnus::wrap(class = "TreeStump::QueryMatch", free_immediately)]
+fn build_property(ruby: &Ruby, property: &tree_sitter::QueryProperty) -> Result<Value, Error> {
+    let struct_class = QUERY_PROPERTY_CLASS.get_inner_ref_with(ruby);
+    struct_class.new_instance((
+        &*property.key,
+        property.value.as_deref(),
+        property.capture_id,
+    ))
+}
+
+#[derive(TypedData)]
+#[magnus(class = "TreeStump::QueryMatch", free_immediately, mark)]
 pub struct QueryMatch {
     pattern_index: usize,
     captures: Opaque<RArray>,
 }
 
+impl DataTypeFunctions for QueryMatch {
+    fn mark(&self, marker: &Marker) {
+        marker.mark(self.captures);
+    }
+}
+
 impl QueryMatch {
+    fn new(
+        ruby: &Ruby,
+        raw_match: &CollectedMatch,
+        raw_tree: &Arc<SharedTree>,
+    ) -> Result<Self, Error> {
+        let struct_class = QUERY_CAPTURE_CLASS.get_inner_ref_with(ruby);
+        let captures = ruby.ary_new_capa(raw_match.captures.len());
+        for c in &raw_match.captures {
+            let r_struct = RStruct::from_value(
+                struct_class.new_instance((Node::new(Arc::clone(raw_tree), c.node), c.index))?,
+            );
+            captures.push(r_struct)?
+        }
+        Ok(Self {
+            pattern_index: raw_match.pattern_index,
+            captures: Opaque::from(captures),
+        })
+    }
+
     pub fn pattern_index(&self) -> usize {
         self.pattern_index
     }
@@ -96,66 +349,87 @@ impl QueryMatch {
 
 #[magnus::wrap(class = "TreeStump::QueryCursor", free_immediately)]
 pub struct QueryCursor {
-    raw_cursor: RefCell<tree_sitter::QueryCursor>,
+    match_limit: Cell<u32>,
+    byte_range: RefCell<Option<Range<usize>>>,
+    point_range: RefCell<Option<Range<tree_sitter::Point>>>,
+    max_start_depth: Cell<Option<u32>>,
+    did_exceed_match_limit: Cell<bool>,
 }
 
 impl QueryCursor {
     pub fn new() -> Self {
         Self {
-            raw_cursor: RefCell::new(tree_sitter::QueryCursor::new()),
+            match_limit: Cell::new(u32::MAX),
+            byte_range: RefCell::new(None),
+            point_range: RefCell::new(None),
+            max_start_depth: Cell::new(None),
+            did_exceed_match_limit: Cell::new(false),
+        }
+    }
+
+    // Every run gets a cursor of its own, configured from these settings, so
+    // changing them only affects later runs.
+    fn build_raw_cursor(&self) -> tree_sitter::QueryCursor {
+        let mut raw_cursor = tree_sitter::QueryCursor::new();
+        raw_cursor.set_match_limit(self.match_limit.get());
+        if let Some(range) = self.byte_range.borrow().clone() {
+            raw_cursor.set_byte_range(range);
         }
+        if let Some(range) = self.point_range.borrow().clone() {
+            raw_cursor.set_point_range(range);
+        }
+        raw_cursor.set_max_start_depth(self.max_start_depth.get());
+        raw_cursor
     }
 
     pub fn match_limit(&self) -> u32 {
-        self.raw_cursor.borrow().match_limit()
+        self.match_limit.get()
     }
 
     pub fn set_match_limit(&self, limit: u32) {
-        self.raw_cursor.borrow_mut().set_match_limit(limit);
+        self.match_limit.set(limit);
     }
 
     pub fn did_exceed_match_limit(&self) -> bool {
-        self.raw_cursor.borrow().did_exceed_match_limit()
+        self.did_exceed_match_limit.get()
     }
 
-    pub fn matches<'tree>(
+    // Matches are collected in small batches without the GVL and yielded one
+    // by one, so memory use stays flat and breaking out of the block stops the
+    // query. Predicates and the block run with the GVL.
+    pub fn matches(
         ruby: &Ruby,
         rb_self: typed_data::Obj<Self>,
-        query: typed_data::Obj<Query>,
-        node: typed_data::Obj<Node<'tree>>,
-        source: String,
-    ) -> Result<Yield<impl Iterator<Item = Value>>, Error> {
-        let mut cursor = rb_self.raw_cursor.borrow_mut();
-        let raw_query = query.raw_query.borrow();
-
-        let matches = cursor.matches(&raw_query, node.get_raw_node(), source.as_bytes());
-        let struct_class = QUERY_CAPTURE_CLASS.get_inner_ref_with(ruby);
-        let array = ruby.ary_new();
-
-        for m in matches {
-            let captures = ruby.ary_new();
-            for c in m.captures {
-                let r_struct = RStruct::from_value(
-                    struct_class
-                        .new_instance((Node::new(Arc::clone(&node.raw_tree), c.node), c.index))?,
-                );
-                captures.push(r_struct)?
-            }
-            let query_match = QueryMatch {
-                pattern_index: m.pattern_index,
-                captures: Opaque::from(captures),
-            };
-            array.push(query_match)?
+        args: &[Value],
+    ) -> Result<Value, Error> {
+        if !ruby.block_given() {
+            return Ok(rb_self.enumeratorize("matches", args).as_value());
         }
 
-        if ruby.block_given() {
-            Ok(Yield::Iter(array.into_iter()))
-        } else {
-            Ok(Yield::Enumerator(rb_self.enumeratorize(
-                "matches",
-                (query, node, source.into_value()),
-            )))
+        let matches = QueryMatches::new(ruby, &rb_self, args, false)?;
+        matches.each(ruby, &rb_self, |query_match, _| {
+            ruby.yield_value::<_, Value>(query_match).map(|_| ())
+        })?;
+
+        Ok(rb_self.as_value())
+    }
+
+    pub fn captures(
+        ruby: &Ruby,
+        rb_self: typed_data::Obj<Self>,
+        args: &[Value],
+    ) -> Result<Value, Error> {
+        if !ruby.block_given() {
+            return Ok(rb_self.enumeratorize("captures", args).as_value());
         }
+
+        let captures = QueryMatches::new(ruby, &rb_self, args, true)?;
+        captures.each(ruby, &rb_self, |query_match, capture_index| {
+            ruby.yield_values::<_, Value>((query_match, capture_index))
+                .map(|_| ())
+        })?;
+
+        Ok(rb_self.as_value())
     }
 
     pub fn set_byte_range(
@@ -163,10 +437,9 @@ impl QueryCursor {
         rb_self: typed_data::Obj<Self>,
         range: magnus::Range,
     ) -> Result<typed_data::Obj<Self>, Error> {
-        let mut cursor = rb_self.raw_cursor.borrow_mut();
         let len = range.funcall("size", ())?;
         let std_range = range.to_range_with_len(len)?;
-        cursor.set_byte_range(std_range);
+        rb_self.byte_range.replace(Some(std_range));
         Ok(rb_self)
     }
 
@@ -192,8 +465,7 @@ impl QueryCursor {
 
         let point_range = start.into_raw()..end.into_raw();
 
-        let mut cursor = rb_self.raw_cursor.borrow_mut();
-        cursor.set_point_range(point_range);
+        rb_self.point_range.replace(Some(point_range));
         Ok(rb_self)
     }
 
@@ -202,8 +474,293 @@ impl QueryCursor {
         rb_self: typed_data::Obj<Self>,
         depth: Option<u32>,
     ) -> Result<typed_data::Obj<Self>, Error> {
-        let mut cursor = rb_self.raw_cursor.borrow_mut();
-        cursor.set_max_start_depth(depth);
+        rb_self.max_start_depth.set(depth);
         Ok(rb_self)
     }
 }
+
+enum RawMatches {
+    Matches(tree_sitter::QueryMatches<'static, 'static, &'static [u8], &'static [u8]>),
+    Captures(tree_sitter::QueryCaptures<'static, 'static, &'static [u8], &'static [u8]>),
+}
+
+// Matches collected per release of the GVL. Re-acquiring it for every single
+// match is slow while other threads compete for it.
+const MATCH_BATCH_SIZE: usize = 64;
+
+// A match copied out of the cursor, which reuses its capture buffers on the
+// next step, so that a batch of them can be collected at once.
+struct CollectedMatch {
+    id: u32,
+    pattern_index: usize,
+    captures: Vec<tree_sitter::QueryCapture<'static>>,
+    capture_index: usize,
+}
+
+impl CollectedMatch {
+    fn new(raw_match: &tree_sitter::QueryMatch<'_, 'static>, capture_index: usize) -> Self {
+        Self {
+            id: raw_match.id(),
+            pattern_index: raw_match.pattern_index,
+            captures: raw_match.captures.to_vec(),
+            capture_index,
+        }
+    }
+
+    fn nodes_for_capture_index(
+        &self,
+        index: u32,
+    ) -> impl Iterator<Item = tree_sitter::Node<'static>> + '_ {
+        self.captures
+            .iter()
+            .filter(move |c| c.index == index)
+            .map(|c| c.node)
+    }
+}
+
+struct MatchesState {
+    // Borrows from the fields below, so it is declared (and dropped) first.
+    raw_matches: RawMatches,
+    raw_cursor: Box<tree_sitter::QueryCursor>,
+    raw_tree: Arc<SharedTree>,
+    text: SourceText,
+    pending: VecDeque<CollectedMatch>,
+    is_exhausted: bool,
+    // `#captures` returns a match once per capture, but its predicates are
+    // only evaluated the first time. Entries hold the result and the number
+    // of captures still to come.
+    predicate_results: HashMap<u32, (bool, usize)>,
+    _running: RunningMatches,
+}
+
+// Counts a run on its query for as long as the run's state is alive.
+struct RunningMatches(Arc<AtomicUsize>);
+
+impl RunningMatches {
+    fn new(count: &Arc<AtomicUsize>) -> Self {
+        count.fetch_add(1, Ordering::SeqCst);
+        Self(Arc::clone(count))
+    }
+}
+
+impl Drop for RunningMatches {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl MatchesState {
+    // Returns whether the cursor has no more matches.
+    fn collect_batch(&mut self) -> bool {
+        while self.pending.len() < MATCH_BATCH_SIZE {
+            let next = match &mut self.raw_matches {
+                RawMatches::Matches(matches) => matches.next().map(|m| CollectedMatch::new(&m, 0)),
+                RawMatches::Captures(captures) => captures
+                    .next()
+                    .map(|(m, capture_index)| CollectedMatch::new(&m, capture_index)),
+            };
+            match next {
+                Some(m) => self.pending.push_back(m),
+                None => return true,
+            }
+        }
+        false
+    }
+}
+
+// One run of `QueryCursor#matches` or `#captures`. It owns everything the run
+// reads, so a run that is suspended (e.g. inside an Enumerator that is never
+// finished) leaves the `QueryCursor` free. The `Query` cannot be changed until
+// the run ends, or the GC frees it along with the Fiber that holds it.
+#[derive(TypedData)]
+#[magnus(class = "TreeStump::QueryMatches", free_immediately, mark)]
+pub struct QueryMatches {
+    query: Opaque<typed_data::Obj<Query>>,
+    // The String given to custom predicates, built on first use when the
+    // source was not passed explicitly.
+    source: Cell<Option<Opaque<RString>>>,
+    // `None` once the run has ended.
+    state: RefCell<Option<MatchesState>>,
+}
+
+// SAFETY: the raw cursor is only used by the thread holding the GVL, or by the
+// single `without_gvl` call that thread is blocked on.
+unsafe impl Send for QueryMatches {}
+
+impl DataTypeFunctions for QueryMatches {
+    fn mark(&self, marker: &Marker) {
+        marker.mark(self.query);
+        if let Some(source) = self.source.get() {
+            marker.mark(source);
+        }
+    }
+}
+
+impl QueryMatches {
+    fn new(
+        ruby: &Ruby,
+        cursor: &QueryCursor,
+        args: &[Value],
+        captures: bool,
+    ) -> Result<typed_data::Obj<Self>, Error> {
+        let (query, node, source) = parse_query_args(args)?;
+        let (source, text) = query_source(&node, source)?;
+        let raw_tree = Arc::clone(&node.raw_tree);
+        let mut raw_cursor = Box::new(cursor.build_raw_cursor());
+
+        let running = RunningMatches::new(&query.running_matches);
+        let raw_query = try_borrow(&query.raw_query, "Query")?;
+        // SAFETY: the `Query` object is marked, so the query outlives the run,
+        // and `disable_*`, which are its only mutations, raise while `running`
+        // is alive. The cursor and text are heap allocations owned by the
+        // state below.
+        let (query_ref, cursor_ref, text_ref) = unsafe {
+            (
+                &*(&*raw_query as *const tree_sitter::Query),
+                &mut *(&mut *raw_cursor as *mut tree_sitter::QueryCursor),
+                &*(text.as_bytes() as *const [u8]),
+            )
+        };
+        let raw_matches = if captures {
+            RawMatches::Captures(cursor_ref.captures(query_ref, node.raw_node, text_ref))
+        } else {
+            RawMatches::Matches(cursor_ref.matches(query_ref, node.raw_node, text_ref))
+        };
+        drop(raw_query);
+
+        Ok(ruby.obj_wrap(Self {
+            query: Opaque::from(query),
+            source: Cell::new(source.map(Opaque::from)),
+            state: RefCell::new(Some(MatchesState {
+                raw_matches,
+                raw_cursor,
+                raw_tree,
+                text,
+                pending: VecDeque::new(),
+                is_exhausted: false,
+                predicate_results: HashMap::new(),
+                _running: running,
+            })),
+        }))
+    }
+
+    fn source(&self, ruby: &Ruby, text: &SourceText) -> RString {
+        if let Some(source) = self.source.get() {
+            return ruby.get_inner(source);
+        }
+        let source = text.to_rstring(ruby);
+        source.freeze();
+        self.source.set(Some(Opaque::from(source)));
+        source
+    }
+
+    // Runs to the end, or until `f` fails, and then ends the run right away
+    // instead of when the GC frees it.
+    fn each(
+        &self,
+        ruby: &Ruby,
+        cursor: &QueryCursor,
+        mut f: impl FnMut(QueryMatch, usize) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let result = (|| -> Result<(), Error> {
+            while let Some((query_match, capture_index)) = self.next(ruby, cursor)? {
+                f(query_match, capture_index)?;
+            }
+            Ok(())
+        })();
+        if let Ok(mut state) = self.state.try_borrow_mut() {
+            *state = None;
+        }
+        result
+    }
+
+    // Advances to the next match that satisfies the query's predicates. For
+    // captures, the index of the current capture within the match is returned
+    // alongside it.
+    fn next(
+        &self,
+        ruby: &Ruby,
+        cursor: &QueryCursor,
+    ) -> Result<Option<(QueryMatch, usize)>, Error> {
+        let query = ruby.get_inner(self.query);
+        let mut state = try_borrow_mut(&self.state, "QueryMatches")?;
+        let Some(state) = state.as_mut() else {
+            return Ok(None);
+        };
+
+        loop {
+            let Some(m) = state.pending.pop_front() else {
+                if state.is_exhausted {
+                    return Ok(None);
+                }
+                state.is_exhausted = without_gvl(|| state.collect_batch());
+                cursor
+                    .did_exceed_match_limit
+                    .set(state.raw_cursor.did_exceed_match_limit());
+                continue;
+            };
+
+            let satisfies_predicates = || {
+                query.satisfies_predicates(
+                    ruby,
+                    &m,
+                    &state.raw_tree,
+                    || self.source(ruby, &state.text),
+                    state.text.as_bytes(),
+                )
+            };
+            let is_satisfied = match state.raw_matches {
+                RawMatches::Matches(_) => satisfies_predicates()?,
+                RawMatches::Captures(_) => {
+                    let (is_satisfied, remaining) = match state.predicate_results.remove(&m.id) {
+                        Some(result) => result,
+                        None => (satisfies_predicates()?, m.captures.len()),
+                    };
+                    if remaining > 1 {
+                        state
+                            .predicate_results
+                            .insert(m.id, (is_satisfied, remaining - 1));
+                    }
+                    is_satisfied
+                }
+            };
+            if is_satisfied {
+                let query_match = QueryMatch::new(ruby, &m, &state.raw_tree)?;
+                return Ok(Some((query_match, m.capture_index)));
+            }
+        }
+    }
+}
+
+type QueryArgs = (
+    typed_data::Obj<Query>,
+    typed_data::Obj<Node<'static>>,
+    Option<RString>,
+);
+
+// `(query, node, source = nil)`
+fn parse_query_args(args: &[Value]) -> Result<QueryArgs, Error> {
+    let args = scan_args::<
+        (typed_data::Obj<Query>, typed_data::Obj<Node<'static>>),
+        (Option<Option<RString>>,),
+        (),
+        (),
+        (),
+        (),
+    >(args)?;
+    let (query, node) = args.required;
+    let (source,) = args.optional;
+    Ok((query, node, source.flatten()))
+}
+
+// Without an explicit source, queries run against the text the node's tree
+// was parsed from. Predicates then receive it as a frozen String.
+fn query_source(
+    node: &Node,
+    source: Option<RString>,
+) -> Result<(Option<RString>, SourceText), Error> {
+    match source {
+        Some(source) => Ok((Some(source), SourceText::new(source))),
+        None => Ok((None, node.raw_tree.source()?.clone())),
+    }
+}
@@ -0,0 +1,77 @@
+use magnus::{scan_args::scan_args, value::InnerRef, Class, Error, RArray, Ruby, Value};
+use tree_sitter_tags::{TagsConfiguration, TagsContext};
+
+use std::cell::RefCell;
+
+use crate::data;
+use crate::language::Language;
+use crate::util::{build_error, build_query_error, try_borrow_mut};
+use crate::{find_language, TAG_CLASS};
+
+#[magnus::wrap(class = "TreeStump::Tagger", free_immediately)]
+pub struct Tagger {
+    raw_context: RefCell<TagsContext>,
+    config: TagsConfiguration,
+    language_name: String,
+    _language: Language,
+}
+
+impl Tagger {
+    pub fn new(args: &[Value]) -> Result<Self, Error> {
+        let args = scan_args::<(String, String), (Option<String>,), (), (), (), ()>(args)?;
+        let (lang, tags_query) = args.required;
+        let (locals_query,) = args.optional;
+
+        let language = find_language(&lang)?;
+        let config = TagsConfiguration::new(
+            language.raw_language.clone(),
+            &tags_query,
+            locals_query.as_deref().unwrap_or_default(),
+        )
+        .map_err(|e| build_query_error(e.to_string()))?;
+
+        Ok(Self {
+            raw_context: RefCell::new(TagsContext::new()),
+            config,
+            language_name: lang,
+            _language: language,
+        })
+    }
+
+    pub fn language_name(&self) -> String {
+        self.language_name.clone()
+    }
+
+    pub fn tags(ruby: &Ruby, rb_self: &Self, source: String) -> Result<RArray, Error> {
+        let source = source.as_bytes();
+        let mut raw_context = try_borrow_mut(&rb_self.raw_context, "Tagger")?;
+        let (tags, _has_error) = raw_context
+            .generate_tags(&rb_self.config, source, None)
+            .map_err(|e| build_error(e.to_string()))?;
+
+        let struct_class = TAG_CLASS.get_inner_ref_with(ruby);
+        let array = ruby.ary_new();
+        for tag in tags {
+            let tag = tag.map_err(|e| build_error(e.to_string()))?;
+            let name = String::from_utf8_lossy(&source[tag.name_range.clone()]);
+            let line = String::from_utf8_lossy(&source[tag.line_range.clone()]);
+            let name_range = data::Range {
+                start_byte: tag.name_range.start,
+                end_byte: tag.name_range.end,
+                start_point: tag.span.start.into(),
+                end_point: tag.span.end.into(),
+            };
+            let value = struct_class.new_instance((
+                rb_self.config.syntax_type_name(tag.syntax_type_id),
+                &*name,
+                tag.is_definition,
+                tag.docs,
+                &*line,
+                ruby.range_new(tag.range.start, tag.range.end, true)?,
+                name_range,
+            ))?;
+            array.push(value)?;
+        }
+        Ok(array)
+    }
+}
@@ -1,61 +1,318 @@
 use magnus::block::Yield;
-use magnus::value::ReprValue;
-use magnus::{typed_data, Error, RFile, Ruby, Value};
+use magnus::gc::Marker;
+use magnus::value::{Opaque, ReprValue};
+use magnus::{
+    encoding, encoding::EncodingCapable, typed_data, DataTypeFunctions, Error, RArray, RFile,
+    RString, Ruby, TypedData, Value,
+};
 
-use std::cell::RefCell;
+use libloading::Library;
+
+use std::cell::{Cell, RefCell};
+use std::fmt;
 use std::hash::Hash;
 use std::num::NonZero;
-use std::ops::Range;
+use std::ops::{Deref, DerefMut, Range};
 use std::sync::Arc;
 
 use crate::data;
 use crate::data::Point;
 use crate::language::LanguageRef;
-use crate::util::build_error;
+use crate::util::{build_error, try_borrow_mut};
+
+// A parsed tree together with the grammar library its language tables live in,
+// so the library stays loaded as long as any tree, node or cursor needs it.
+// It also keeps the source it was parsed from, if the parser had it at once.
+#[derive(Clone)]
+pub struct SharedTree {
+    raw_tree: tree_sitter::Tree,
+    source: Option<SourceText>,
+    library: Option<Arc<Library>>,
+    utf16: bool,
+}
+
+impl SharedTree {
+    pub fn new(
+        raw_tree: tree_sitter::Tree,
+        library: Option<Arc<Library>>,
+        source: Option<SourceText>,
+        utf16: bool,
+    ) -> Self {
+        Self {
+            raw_tree,
+            source,
+            library,
+            utf16,
+        }
+    }
+
+    pub fn library(&self) -> Option<Arc<Library>> {
+        self.library.clone()
+    }
+
+    pub fn source(&self) -> Result<&SourceText, Error> {
+        self.source.as_ref().ok_or_else(no_source_error)
+    }
+
+    pub fn without_source(&self) -> Self {
+        Self {
+            raw_tree: self.raw_tree.clone(),
+            source: None,
+            library: self.library.clone(),
+            utf16: self.utf16,
+        }
+    }
+
+    // Columns of a UTF-16 tree already count UTF-16 bytes, so converting them
+    // as UTF-8 byte columns would give wrong positions.
+    pub fn check_utf8(&self) -> Result<(), Error> {
+        if self.utf16 {
+            return Err(build_error(
+                "Column conversions are only available on trees parsed as UTF-8",
+            ));
+        }
+        Ok(())
+    }
+
+    // The source no longer matches the offsets of an edited tree.
+    pub fn edit(&mut self, edit: &tree_sitter::InputEdit) {
+        self.raw_tree.edit(edit);
+        self.source = None;
+    }
+}
+
+// The bytes of a source String as given, so that byte offsets of the tree
+// match them and text is returned in the String's encoding.
+#[derive(Clone)]
+pub struct SourceText {
+    bytes: Arc<[u8]>,
+    encoding: encoding::Index,
+}
+
+impl SourceText {
+    pub fn new(source: RString) -> Self {
+        Self {
+            // SAFETY: the bytes are copied before Ruby can run again.
+            bytes: Arc::from(unsafe { source.as_slice() }),
+            encoding: source.enc_get(),
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn to_rstring(&self, ruby: &Ruby) -> RString {
+        ruby.enc_str_new(&*self.bytes, self.encoding)
+    }
+}
+
+fn no_source_error() -> Error {
+    build_error("The tree was parsed without its source text, so it must be passed explicitly")
+}
+
+fn source_slice(source: &[u8], range: Range<usize>) -> Result<&[u8], Error> {
+    source.get(range.clone()).ok_or_else(|| {
+        build_error(format!(
+            "Node's byte range {}...{} is outside of the source ({} bytes)",
+            range.start,
+            range.end,
+            source.len()
+        ))
+    })
+}
+
+impl Deref for SharedTree {
+    type Target = tree_sitter::Tree;
+
+    fn deref(&self) -> &Self::Target {
+        &self.raw_tree
+    }
+}
+
+impl DerefMut for SharedTree {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.raw_tree
+    }
+}
 
-#[magnus::wrap(class = "TreeStump::Tree", free_immediately)]
+impl fmt::Debug for SharedTree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.raw_tree.fmt(f)
+    }
+}
+
+#[derive(TypedData)]
+#[magnus(class = "TreeStump::Tree", free_immediately, mark)]
 pub struct Tree {
-    raw_tree: Arc<tree_sitter::Tree>,
+    // Nodes and cursors share the inner tree, so edits swap in a new `Arc`
+    // instead of mutating a tree that other Ruby objects still point into.
+    raw_tree: RefCell<Arc<SharedTree>>,
+    // The String returned by `Tree#source`, built on first use.
+    source: Cell<Option<Opaque<RString>>>,
+    // The copy without source behind `root_node_with_offset`, shared by its
+    // nodes so that the same node from two calls is `==`.
+    offset_tree: RefCell<Option<Arc<SharedTree>>>,
+}
+
+impl DataTypeFunctions for Tree {
+    fn mark(&self, marker: &Marker) {
+        if let Some(source) = self.source.get() {
+            marker.mark(source);
+        }
+    }
 }
 
 impl Tree {
-    pub fn from(raw_tree: Arc<tree_sitter::Tree>) -> Self {
-        Self { raw_tree }
+    pub fn from(raw_tree: Arc<SharedTree>) -> Self {
+        Self {
+            raw_tree: RefCell::new(raw_tree),
+            source: Cell::new(None),
+            offset_tree: RefCell::new(None),
+        }
+    }
+
+    pub fn get_raw_tree(&self) -> Arc<SharedTree> {
+        Arc::clone(&self.raw_tree.borrow())
     }
 
     pub fn root_node(&self) -> Node<'_> {
-        Node {
-            raw_tree: Arc::clone(&self.raw_tree),
-            raw_node: self.raw_tree.root_node(),
-        }
+        let raw_tree = self.get_raw_tree();
+        // SAFETY: the node borrows from `raw_tree`, which the `Node` keeps alive.
+        let raw_node = unsafe {
+            std::mem::transmute::<tree_sitter::Node<'_>, tree_sitter::Node<'_>>(
+                raw_tree.root_node(),
+            )
+        };
+        Node { raw_tree, raw_node }
+    }
+
+    // Positions of the returned nodes are shifted by the offset, e.g. to the
+    // place of an embedded fragment in its host document. Their byte ranges no
+    // longer match the source, so they come from a copy of the tree without it.
+    pub fn root_node_with_offset(&self, offset_bytes: usize, offset_extent: &Point) -> Node<'_> {
+        let raw_tree = Arc::clone(
+            self.offset_tree
+                .borrow_mut()
+                .get_or_insert_with(|| Arc::new(self.get_raw_tree().without_source())),
+        );
+        // SAFETY: the node borrows from `raw_tree`, which the `Node` keeps alive.
+        let raw_node = unsafe {
+            std::mem::transmute::<tree_sitter::Node<'_>, tree_sitter::Node<'_>>(
+                raw_tree.root_node_with_offset(offset_bytes, offset_extent.into_raw()),
+            )
+        };
+        Node { raw_tree, raw_node }
     }
 
     pub fn language(&self) -> LanguageRef<'_> {
-        let raw_lang_ref = self.raw_tree.language();
+        let raw_tree = self.get_raw_tree();
+        // SAFETY: the language tables live in the grammar library, which the
+        // `LanguageRef` keeps loaded.
+        let raw_lang_ref = unsafe {
+            std::mem::transmute::<tree_sitter::LanguageRef<'_>, tree_sitter::LanguageRef<'_>>(
+                raw_tree.language(),
+            )
+        };
         LanguageRef {
             raw_language_ref: raw_lang_ref,
+            library: raw_tree.library(),
         }
     }
 
     pub fn walk(&self) -> TreeCursor<'_> {
+        let raw_tree = self.get_raw_tree();
+        // SAFETY: the cursor borrows from `raw_tree`, which the `TreeCursor` keeps alive.
+        let raw_cursor = unsafe {
+            std::mem::transmute::<tree_sitter::TreeCursor<'_>, tree_sitter::TreeCursor<'_>>(
+                raw_tree.walk(),
+            )
+        };
         TreeCursor {
-            raw_tree: Arc::clone(&self.raw_tree),
-            raw_cursor: RefCell::new(self.raw_tree.walk()),
+            raw_tree,
+            raw_cursor: RefCell::new(raw_cursor),
+        }
+    }
+
+    // tree-sitter copies are cheap: the copy shares the immutable subtrees.
+    pub fn copy(&self) -> Self {
+        let raw_tree = self.raw_tree.borrow();
+        Self::from(Arc::new(SharedTree::clone(&raw_tree)))
+    }
+
+    pub fn edit(&self, edit: &data::InputEdit) {
+        let mut raw_tree = self.raw_tree.borrow_mut();
+        // Copy-on-write: nodes taken before the edit keep seeing the old tree.
+        Arc::make_mut(&mut raw_tree).edit(&edit.into_raw());
+        self.source.set(None);
+        self.offset_tree.replace(None);
+    }
+
+    pub fn changed_ranges(
+        ruby: &Ruby,
+        rb_self: typed_data::Obj<Self>,
+        other: typed_data::Obj<Tree>,
+    ) -> Result<Yield<impl Iterator<Item = Value>>, Error> {
+        let raw_tree = rb_self.get_raw_tree();
+        let other_raw_tree = other.get_raw_tree();
+        let ranges = raw_tree.changed_ranges(&other_raw_tree);
+        let array = ruby.ary_new_capa(ranges.len());
+        for r in ranges {
+            array.push(data::Range::from(r))?
         }
+        array.freeze();
+
+        if ruby.block_given() {
+            Ok(Yield::Iter(array.into_iter()))
+        } else {
+            Ok(Yield::Enumerator(
+                rb_self.enumeratorize("changed_ranges", [other]),
+            ))
+        }
+    }
+
+    // A frozen copy of the source the tree was parsed from, or nil for trees
+    // from `parse_with`, UTF-16 parses and edits.
+    pub fn source(ruby: &Ruby, rb_self: &Self) -> Option<RString> {
+        if let Some(source) = rb_self.source.get() {
+            return Some(ruby.get_inner(source));
+        }
+        let source = rb_self.raw_tree.borrow().source.as_ref()?.to_rstring(ruby);
+        source.freeze();
+        rb_self.source.set(Some(Opaque::from(source)));
+        Some(source)
+    }
+
+    // Like `source`, but raises for trees without one, as `Node#text` does.
+    pub fn text(ruby: &Ruby, rb_self: &Self) -> Result<RString, Error> {
+        Self::source(ruby, rb_self).ok_or_else(no_source_error)
+    }
+
+    pub fn byte_size(&self) -> usize {
+        self.raw_tree.borrow().root_node().end_byte()
+    }
+
+    pub fn included_ranges(&self) -> Vec<data::Range> {
+        let raw_tree = self.raw_tree.borrow();
+        raw_tree
+            .included_ranges()
+            .into_iter()
+            .map(data::Range::from)
+            .collect()
     }
 
     pub fn print_dot_graph(&self, io: RFile) {
-        self.raw_tree.print_dot_graph(&io);
+        self.raw_tree.borrow().print_dot_graph(&io);
     }
 
     pub fn inspect(&self) -> String {
-        format!("{:?}", self.raw_tree)
+        format!("{:?}", self.raw_tree.borrow())
     }
 }
 
 #[magnus::wrap(class = "TreeStump::TreeCursor", free_immediately, unsafe_generics)]
 pub struct TreeCursor<'cursor> {
-    raw_tree: Arc<tree_sitter::Tree>,
+    raw_tree: Arc<SharedTree>,
     raw_cursor: RefCell<tree_sitter::TreeCursor<'cursor>>,
 }
 
@@ -118,13 +375,15 @@ impl<'cursor> TreeCursor<'cursor> {
 #[magnus::wrap(class = "TreeStump::Node", free_immediately, unsafe_generics)]
 #[derive(Debug, Clone)]
 pub struct Node<'tree> {
-    pub raw_tree: Arc<tree_sitter::Tree>,
+    pub raw_tree: Arc<SharedTree>,
     pub raw_node: tree_sitter::Node<'tree>,
 }
 
+// Copies of a tree share their subtrees, so nodes are only equal when they
+// also come from the same tree.
 impl PartialEq for Node<'_> {
     fn eq(&self, other: &Self) -> bool {
-        self.raw_node == other.raw_node
+        Arc::ptr_eq(&self.raw_tree, &other.raw_tree) && self.raw_node == other.raw_node
     }
 }
 
@@ -132,12 +391,13 @@ impl Eq for Node<'_> {}
 
 impl Hash for Node<'_> {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        Arc::as_ptr(&self.raw_tree).hash(state);
         self.raw_node.hash(state)
     }
 }
 
 impl<'tree> Node<'tree> {
-    pub fn new(raw_tree: Arc<tree_sitter::Tree>, raw_node: tree_sitter::Node<'tree>) -> Self {
+    pub fn new(raw_tree: Arc<SharedTree>, raw_node: tree_sitter::Node<'tree>) -> Self {
         Self { raw_tree, raw_node }
     }
 
@@ -169,6 +429,7 @@ impl<'tree> Node<'tree> {
         let raw_lang_ref = self.raw_node.language();
         LanguageRef {
             raw_language_ref: raw_lang_ref,
+            library: self.raw_tree.library(),
         }
     }
 
@@ -192,12 +453,16 @@ impl<'tree> Node<'tree> {
         self.raw_node.is_error()
     }
 
+    pub fn is_missing(&self) -> bool {
+        self.raw_node.is_missing()
+    }
+
     pub fn parse_state(&self) -> u16 {
         self.raw_node.parse_state()
     }
 
     pub fn next_parse_state(&self) -> u16 {
-        self.raw_node.parse_state()
+        self.raw_node.next_parse_state()
     }
 
     pub fn start_byte(&self) -> usize {
@@ -224,6 +489,30 @@ impl<'tree> Node<'tree> {
         self.raw_node.end_position().into()
     }
 
+    pub fn start_position_utf16(&self, source: RString) -> Result<Point, Error> {
+        self.raw_tree.check_utf8()?;
+        let point = self.start_position();
+        Ok(Point::new(point.row, point.utf16_column(source)?))
+    }
+
+    pub fn end_position_utf16(&self, source: RString) -> Result<Point, Error> {
+        self.raw_tree.check_utf8()?;
+        let point = self.end_position();
+        Ok(Point::new(point.row, point.utf16_column(source)?))
+    }
+
+    pub fn start_position_chars(&self, source: RString) -> Result<Point, Error> {
+        self.raw_tree.check_utf8()?;
+        let point = self.start_position();
+        Ok(Point::new(point.row, point.char_column(source)?))
+    }
+
+    pub fn end_position_chars(&self, source: RString) -> Result<Point, Error> {
+        self.raw_tree.check_utf8()?;
+        let point = self.end_position();
+        Ok(Point::new(point.row, point.char_column(source)?))
+    }
+
     pub fn child(&self, index: usize) -> Option<Self> {
         self.raw_node.child(index).map(|node| Self {
             raw_tree: Arc::clone(&self.raw_tree),
@@ -294,7 +583,7 @@ impl<'tree> Node<'tree> {
         rb_self: typed_data::Obj<Self>,
         cursor: typed_data::Obj<TreeCursor<'tree>>,
     ) -> Result<Yield<impl Iterator<Item = Value>>, Error> {
-        let mut borrowed = cursor.raw_cursor.borrow_mut();
+        let mut borrowed = try_borrow_mut(&cursor.raw_cursor, "TreeCursor")?;
         let nodes = rb_self.raw_node.children(&mut borrowed);
         let array = ruby.ary_new_capa(nodes.len());
         for n in nodes {
@@ -310,7 +599,7 @@ impl<'tree> Node<'tree> {
             Ok(Yield::Iter(array.into_iter()))
         } else {
             Ok(Yield::Enumerator(
-                rb_self.enumeratorize("children_with_cursor", ()),
+                rb_self.enumeratorize("children_with_cursor", [cursor]),
             ))
         }
     }
@@ -320,7 +609,7 @@ impl<'tree> Node<'tree> {
         rb_self: typed_data::Obj<Self>,
         cursor: typed_data::Obj<TreeCursor<'tree>>,
     ) -> Result<Yield<impl Iterator<Item = Value>>, Error> {
-        let mut borrowed = cursor.raw_cursor.borrow_mut();
+        let mut borrowed = try_borrow_mut(&cursor.raw_cursor, "TreeCursor")?;
         let nodes = rb_self.raw_node.named_children(&mut borrowed);
         let array = ruby.ary_new_capa(nodes.len());
         for n in nodes {
@@ -347,7 +636,7 @@ impl<'tree> Node<'tree> {
         field_name: String,
         cursor: typed_data::Obj<TreeCursor<'tree>>,
     ) -> Result<Yield<impl Iterator<Item = Value>>, Error> {
-        let mut borrowed = cursor.raw_cursor.borrow_mut();
+        let mut borrowed = try_borrow_mut(&cursor.raw_cursor, "TreeCursor")?;
         let nodes = rb_self
             .raw_node
             .children_by_field_name(&field_name, &mut borrowed);
@@ -364,9 +653,10 @@ impl<'tree> Node<'tree> {
         if ruby.block_given() {
             Ok(Yield::Iter(array.into_iter()))
         } else {
-            Ok(Yield::Enumerator(
-                rb_self.enumeratorize("named_children_with_cursor", [cursor]),
-            ))
+            Ok(Yield::Enumerator(rb_self.enumeratorize(
+                "children_by_field_name_with_cursor",
+                (field_name, cursor),
+            )))
         }
     }
 
@@ -376,7 +666,7 @@ impl<'tree> Node<'tree> {
         field_id: u16,
         cursor: typed_data::Obj<TreeCursor<'tree>>,
     ) -> Result<Yield<impl Iterator<Item = Value>>, Error> {
-        let mut borrowed = cursor.raw_cursor.borrow_mut();
+        let mut borrowed = try_borrow_mut(&cursor.raw_cursor, "TreeCursor")?;
         let non_zero_field_id = match NonZero::new(field_id) {
             Some(id) => Ok(id),
             None => Err(build_error("field_id must be non-zero")),
@@ -394,15 +684,104 @@ impl<'tree> Node<'tree> {
         }
         array.freeze();
 
+        if ruby.block_given() {
+            Ok(Yield::Iter(array.into_iter()))
+        } else {
+            Ok(Yield::Enumerator(rb_self.enumeratorize(
+                "children_by_field_id_with_cursor",
+                (field_id, cursor),
+            )))
+        }
+    }
+
+    pub fn named_children(
+        ruby: &Ruby,
+        rb_self: typed_data::Obj<Self>,
+    ) -> Result<Yield<impl Iterator<Item = Value>>, Error> {
+        let mut cursor = rb_self.raw_node.walk();
+        let array = node_array(ruby, &rb_self, rb_self.raw_node.named_children(&mut cursor))?;
         if ruby.block_given() {
             Ok(Yield::Iter(array.into_iter()))
         } else {
             Ok(Yield::Enumerator(
-                rb_self.enumeratorize("named_children_with_cursor", [cursor]),
+                rb_self.enumeratorize("named_children", ()),
+            ))
+        }
+    }
+
+    // Unlike `child_by_field_name`, returns every child of a repeated field.
+    pub fn children_by_field_name(
+        ruby: &Ruby,
+        rb_self: typed_data::Obj<Self>,
+        field_name: String,
+    ) -> Result<Yield<impl Iterator<Item = Value>>, Error> {
+        let mut cursor = rb_self.raw_node.walk();
+        let nodes = rb_self
+            .raw_node
+            .children_by_field_name(&field_name, &mut cursor);
+        let array = node_array(ruby, &rb_self, nodes)?;
+        if ruby.block_given() {
+            Ok(Yield::Iter(array.into_iter()))
+        } else {
+            Ok(Yield::Enumerator(
+                rb_self.enumeratorize("children_by_field_name", (field_name,)),
+            ))
+        }
+    }
+
+    pub fn children_by_field_id(
+        ruby: &Ruby,
+        rb_self: typed_data::Obj<Self>,
+        field_id: u16,
+    ) -> Result<Yield<impl Iterator<Item = Value>>, Error> {
+        let non_zero_field_id =
+            NonZero::new(field_id).ok_or_else(|| build_error("field_id must be non-zero"))?;
+        let mut cursor = rb_self.raw_node.walk();
+        let nodes = rb_self
+            .raw_node
+            .children_by_field_id(non_zero_field_id, &mut cursor);
+        let array = node_array(ruby, &rb_self, nodes)?;
+        if ruby.block_given() {
+            Ok(Yield::Iter(array.into_iter()))
+        } else {
+            Ok(Yield::Enumerator(
+                rb_self.enumeratorize("children_by_field_id", (field_id,)),
             ))
         }
     }
 
+    // The first child that contains or starts after the byte offset.
+    pub fn first_child_for_byte(&self, byte: usize) -> Option<Self> {
+        // tree-sitter offsets are u32, so no child extends past a larger byte.
+        let byte = u32::try_from(byte).ok()?;
+        // SAFETY: the raw node comes from a live node of this tree.
+        let raw_node = unsafe {
+            tree_sitter::ffi::ts_node_first_child_for_byte(self.raw_node.into_raw(), byte)
+        };
+        self.from_raw_node(raw_node)
+    }
+
+    pub fn first_named_child_for_byte(&self, byte: usize) -> Option<Self> {
+        let byte = u32::try_from(byte).ok()?;
+        // SAFETY: the raw node comes from a live node of this tree.
+        let raw_node = unsafe {
+            tree_sitter::ffi::ts_node_first_named_child_for_byte(self.raw_node.into_raw(), byte)
+        };
+        self.from_raw_node(raw_node)
+    }
+
+    // The C API returns a null node where the Rust API returns `None`.
+    fn from_raw_node(&self, raw_node: tree_sitter::ffi::TSNode) -> Option<Self> {
+        if raw_node.id.is_null() {
+            return None;
+        }
+        Some(Self {
+            raw_tree: Arc::clone(&self.raw_tree),
+            // SAFETY: the node is not null and belongs to `raw_tree`.
+            raw_node: unsafe { tree_sitter::Node::from_raw(raw_node) },
+        })
+    }
+
     pub fn parent(&self) -> Option<Self> {
         self.raw_node.parent().map(|node| Self {
             raw_tree: Arc::clone(&self.raw_tree),
@@ -496,7 +875,7 @@ impl<'tree> Node<'tree> {
         let start = tree_sitter::Point::new(start.0, start.1);
         let end = tree_sitter::Point::new(end.0, end.1);
         self.raw_node
-            .descendant_for_point_range(start, end)
+            .named_descendant_for_point_range(start, end)
             .map(|node| Self {
                 raw_tree: Arc::clone(&self.raw_tree),
                 raw_node: node,
@@ -507,11 +886,24 @@ impl<'tree> Node<'tree> {
         self.raw_node.to_sexp()
     }
 
-    pub fn utf8_text(&self, source: String) -> String {
-        self.raw_node
-            .utf8_text(source.as_bytes())
-            .unwrap()
-            .to_string()
+    // Returns the text in the encoding of `source`, whose bytes the node's
+    // byte range refers to.
+    pub fn utf8_text(ruby: &Ruby, rb_self: &Self, source: RString) -> Result<RString, Error> {
+        // SAFETY: the bytes are copied into the new String before Ruby can run again.
+        let text = source_slice(unsafe { source.as_slice() }, rb_self.raw_node.byte_range())?;
+        Ok(ruby.enc_str_new(text, source.enc_get()))
+    }
+
+    pub fn byte_slice(ruby: &Ruby, rb_self: &Self, source: RString) -> Result<RString, Error> {
+        // SAFETY: the bytes are copied into the new String before Ruby can run again.
+        let bytes = source_slice(unsafe { source.as_slice() }, rb_self.raw_node.byte_range())?;
+        Ok(ruby.str_from_slice(bytes))
+    }
+
+    pub fn text(ruby: &Ruby, rb_self: &Self) -> Result<RString, Error> {
+        let source = rb_self.raw_tree.source()?;
+        let text = source_slice(source.as_bytes(), rb_self.raw_node.byte_range())?;
+        Ok(ruby.enc_str_new(text, source.encoding))
     }
 
     pub fn walk(&self) -> TreeCursor {
@@ -529,3 +921,19 @@ impl<'tree> Node<'tree> {
         format!("{}", self.raw_node)
     }
 }
+
+fn node_array<'tree>(
+    ruby: &Ruby,
+    node: &Node<'tree>,
+    nodes: impl Iterator<Item = tree_sitter::Node<'tree>>,
+) -> Result<RArray, Error> {
+    let array = ruby.ary_new();
+    for raw_node in nodes {
+        array.push(Node {
+            raw_tree: Arc::clone(&node.raw_tree),
+            raw_node,
+        })?
+    }
+    array.freeze();
+    Ok(array)
+}
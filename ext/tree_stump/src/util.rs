@@ -1,4 +1,8 @@
 use std::borrow::Cow;
+use std::cell::{Ref, RefCell, RefMut};
+use std::ffi::c_void;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use magnus::{
     gc::register_mark_object,
@@ -6,14 +10,167 @@ use magnus::{
     ExceptionClass, Ruby,
 };
 
-static ERROR_CLASS: Lazy<ExceptionClass> = Lazy::new(|ruby| {
-    let ex = ExceptionClass::from_value(ruby.eval("TreeStump::Error").unwrap()).unwrap();
+fn lookup_error_class(ruby: &Ruby, name: &str) -> ExceptionClass {
+    let ex = ExceptionClass::from_value(ruby.eval(name).unwrap()).unwrap();
     register_mark_object(ex);
     ex
-});
+}
+
+static ERROR_CLASS: Lazy<ExceptionClass> =
+    Lazy::new(|ruby| lookup_error_class(ruby, "TreeStump::Error"));
 
 pub fn build_error(message: impl Into<Cow<'static, str>>) -> magnus::Error {
     let ruby = Ruby::get().expect("Not in Ruby thread");
     let error_class = ERROR_CLASS.get_inner_with(&ruby);
     magnus::Error::new(error_class, message)
 }
+
+static QUERY_ERROR_CLASS: Lazy<ExceptionClass> =
+    Lazy::new(|ruby| lookup_error_class(ruby, "TreeStump::QueryError"));
+
+pub fn build_query_error(message: impl Into<Cow<'static, str>>) -> magnus::Error {
+    let ruby = Ruby::get().expect("Not in Ruby thread");
+    let error_class = QUERY_ERROR_CLASS.get_inner_with(&ruby);
+    magnus::Error::new(error_class, message)
+}
+
+static LANGUAGE_LOAD_ERROR_CLASS: Lazy<ExceptionClass> =
+    Lazy::new(|ruby| lookup_error_class(ruby, "TreeStump::LanguageLoadError"));
+
+pub fn build_language_load_error(message: impl Into<Cow<'static, str>>) -> magnus::Error {
+    let ruby = Ruby::get().expect("Not in Ruby thread");
+    let error_class = LANGUAGE_LOAD_ERROR_CLASS.get_inner_with(&ruby);
+    magnus::Error::new(error_class, message)
+}
+
+static SYMBOL_NOT_FOUND_ERROR_CLASS: Lazy<ExceptionClass> =
+    Lazy::new(|ruby| lookup_error_class(ruby, "TreeStump::SymbolNotFoundError"));
+
+pub fn build_symbol_not_found_error(message: impl Into<Cow<'static, str>>) -> magnus::Error {
+    let ruby = Ruby::get().expect("Not in Ruby thread");
+    let error_class = SYMBOL_NOT_FOUND_ERROR_CLASS.get_inner_with(&ruby);
+    magnus::Error::new(error_class, message)
+}
+
+static PARSE_CANCELLED_CLASS: Lazy<ExceptionClass> =
+    Lazy::new(|ruby| lookup_error_class(ruby, "TreeStump::ParseCancelled"));
+
+pub fn build_parse_cancelled_error(message: impl Into<Cow<'static, str>>) -> magnus::Error {
+    let ruby = Ruby::get().expect("Not in Ruby thread");
+    let error_class = PARSE_CANCELLED_CLASS.get_inner_with(&ruby);
+    magnus::Error::new(error_class, message)
+}
+
+static PARSE_TIMEOUT_CLASS: Lazy<ExceptionClass> =
+    Lazy::new(|ruby| lookup_error_class(ruby, "TreeStump::ParseTimeout"));
+
+pub fn build_parse_timeout_error(message: impl Into<Cow<'static, str>>) -> magnus::Error {
+    let ruby = Ruby::get().expect("Not in Ruby thread");
+    let error_class = PARSE_TIMEOUT_CLASS.get_inner_with(&ruby);
+    magnus::Error::new(error_class, message)
+}
+
+// Objects can be reached from another Ruby thread while one thread runs
+// without the GVL (or from a block while they are in use), so borrows are
+// checked and reported as errors instead of panicking.
+pub fn try_borrow<'a, T>(cell: &'a RefCell<T>, name: &str) -> Result<Ref<'a, T>, magnus::Error> {
+    cell.try_borrow().map_err(|_| {
+        build_error(format!(
+            "{} is already in use by another thread or block",
+            name
+        ))
+    })
+}
+
+pub fn try_borrow_mut<'a, T>(
+    cell: &'a RefCell<T>,
+    name: &str,
+) -> Result<RefMut<'a, T>, magnus::Error> {
+    cell.try_borrow_mut().map_err(|_| {
+        build_error(format!(
+            "{} is already in use by another thread or block",
+            name
+        ))
+    })
+}
+
+struct WithoutGvl<F, R> {
+    func: Option<F>,
+    result: Option<std::thread::Result<R>>,
+}
+
+unsafe extern "C" fn call_without_gvl<F, R>(data: *mut c_void) -> *mut c_void
+where
+    F: FnOnce() -> R,
+{
+    let call = &mut *(data as *mut WithoutGvl<F, R>);
+    if let Some(func) = call.func.take() {
+        call.result = Some(panic::catch_unwind(AssertUnwindSafe(func)));
+    }
+    std::ptr::null_mut()
+}
+
+// Set in a cancellation flag next to any value from `Parser#cancel!`, so that
+// an interrupt can be told apart from a cancellation.
+pub const INTERRUPT_FLAG: usize = 1 << (usize::BITS - 1);
+
+unsafe extern "C" fn interrupt(data: *mut c_void) {
+    let flag = &*(data as *const AtomicUsize);
+    flag.fetch_or(INTERRUPT_FLAG, Ordering::SeqCst);
+}
+
+// Runs `func` with the GVL released so other Ruby threads can run meanwhile.
+// `func` must not touch any Ruby object.
+pub fn without_gvl<F, R>(func: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    call_without_gvl_with(func, None, std::ptr::null_mut())
+}
+
+// Like `without_gvl`, but an interrupt (Ctrl-C, `Thread#raise`, ...) sets
+// `INTERRUPT_FLAG` in `cancellation_flag` so that tree-sitter stops early.
+// Callers should check pending interrupts afterwards with
+// `Ruby::thread_check_ints`, and resume if none is raised.
+pub fn without_gvl_cancellable<F, R>(func: F, cancellation_flag: &AtomicUsize) -> R
+where
+    F: FnOnce() -> R,
+{
+    call_without_gvl_with(
+        func,
+        Some(interrupt),
+        cancellation_flag as *const AtomicUsize as *mut c_void,
+    )
+}
+
+fn call_without_gvl_with<F, R>(
+    func: F,
+    unblock: rb_sys::rb_unblock_function_t,
+    unblock_data: *mut c_void,
+) -> R
+where
+    F: FnOnce() -> R,
+{
+    let mut call = WithoutGvl {
+        func: Some(func),
+        result: None,
+    };
+    // The `2` variant never raises by itself, so no Ruby exception can unwind
+    // through Rust frames here.
+    unsafe {
+        rb_sys::rb_thread_call_without_gvl2(
+            Some(call_without_gvl::<F, R>),
+            &mut call as *mut WithoutGvl<F, R> as *mut c_void,
+            unblock,
+            unblock_data,
+        );
+    }
+    // Skipped when an interrupt was already pending; run it with the GVL then.
+    if let Some(func) = call.func.take() {
+        return func();
+    }
+    match call.result.expect("function was not called without GVL") {
+        Ok(result) => result,
+        Err(panic) => panic::resume_unwind(panic),
+    }
+}
@@ -10,6 +10,17 @@ module TreeStump
   end
 
   class QueryError < Error; end
+  class LanguageLoadError < Error; end
+  class SymbolNotFoundError < LanguageLoadError; end
+  class ParseCancelled < Error; end
+  class ParseTimeout < Error; end
 end
 
 require_relative "tree_stump/tree_stump"
+require_relative "tree_stump/parser"
+require_relative "tree_stump/diagnostic"
+require_relative "tree_stump/tree"
+require_relative "tree_stump/language"
+require_relative "tree_stump/highlighter"
+require_relative "tree_stump/tagger"
+require_relative "tree_stump/loader"
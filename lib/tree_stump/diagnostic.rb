@@ -0,0 +1,22 @@
+# frozen_string_literal: true
+
+module TreeStump
+  # A syntax error of a tree. +kind+ is :error for an ERROR node (unexpected
+  # text) or :missing for a token tree-sitter inserted to recover. +expected+
+  # lists the node kinds that would have been valid at that position.
+  Diagnostic = Struct.new(:kind, :node, :range, :expected, :message, keyword_init: true) do
+    def error?
+      kind == :error
+    end
+
+    def missing?
+      kind == :missing
+    end
+
+    # "3:5: Missing )", with 1-based rows and columns like compilers print them.
+    def to_s
+      point = range.start_point
+      "#{point.row + 1}:#{point.column + 1}: #{message}"
+    end
+  end
+end
@@ -0,0 +1,113 @@
+# frozen_string_literal: true
+
+require "cgi"
+
+module TreeStump
+  class Highlighter
+    QUERY_FILES = {
+      highlights: "highlights.scm",
+      injections: "injections.scm",
+      locals: "locals.scm",
+    }.freeze
+
+    # Builds a highlighter from the query files in +queries_dir+
+    # (e.g. tree-sitter-ruby/queries). Missing injections/locals are allowed.
+    def self.load(lang, queries_dir, **options)
+      queries = QUERY_FILES.each_with_object({}) do |(key, file), h|
+        path = File.join(queries_dir, file)
+        h[key] = File.read(path) if File.exist?(path)
+      end
+      raise Error, "highlights.scm is not found in #{queries_dir}" unless queries[:highlights]
+
+      new(lang, **queries, **options)
+    end
+
+    def to_html(source)
+      HtmlRenderer.new.render(source, highlight(source))
+    end
+
+    def to_ansi(source, theme: AnsiRenderer::DEFAULT_THEME)
+      AnsiRenderer.new(theme: theme).render(source, highlight(source))
+    end
+
+    class HtmlRenderer
+      def initialize(class_prefix: "")
+        @class_prefix = class_prefix
+      end
+
+      # "function.method" becomes class="function method".
+      def render(source, events)
+        events.each_with_object(+"") do |event, out|
+          case event.type
+          when :start
+            classes = event.name.split(".").map { |name| "#{@class_prefix}#{name}" }.join(" ")
+            out << %(<span class="#{classes}">)
+          when :source
+            out << CGI.escapeHTML(source.byteslice(event.start_byte...event.end_byte))
+          when :end
+            out << "</span>"
+          end
+        end
+      end
+    end
+
+    class AnsiRenderer
+      RESET = "\e[0m"
+
+      DEFAULT_THEME = {
+        "attribute" => "33",
+        "comment" => "90",
+        "constant" => "35",
+        "constant.builtin" => "1;35",
+        "constructor" => "33",
+        "embedded" => "39",
+        "escape" => "36",
+        "function" => "34",
+        "keyword" => "31",
+        "label" => "36",
+        "number" => "1;34",
+        "operator" => "1;31",
+        "property" => "36",
+        "punctuation" => "39",
+        "string" => "32",
+        "string.special" => "36",
+        "type" => "33",
+        "variable" => "39",
+        "variable.builtin" => "1;39",
+        "variable.parameter" => "4;39",
+      }.freeze
+
+      def initialize(theme: DEFAULT_THEME)
+        @theme = theme
+      end
+
+      def render(source, events)
+        stack = []
+        events.each_with_object(+"") do |event, out|
+          case event.type
+          when :start
+            stack.push(style_for(event.name))
+          when :source
+            text = source.byteslice(event.start_byte...event.end_byte)
+            style = stack.reverse_each.find(&:itself)
+            out << (style ? "\e[#{style}m#{text}#{RESET}" : text)
+          when :end
+            stack.pop
+          end
+        end
+      end
+
+      private
+
+      # Falls back to the longest dotted prefix, so "function.method" uses "function".
+      def style_for(name)
+        parts = name.split(".")
+        parts.length.downto(1) do |n|
+          style = @theme[parts.take(n).join(".")]
+          return style if style
+        end
+        nil
+      end
+    end
+  end
+end
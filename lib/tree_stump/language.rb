@@ -0,0 +1,13 @@
+# frozen_string_literal: true
+
+module TreeStump
+  class NodeKind
+    def named?
+      named
+    end
+
+    def visible?
+      visible
+    end
+  end
+end
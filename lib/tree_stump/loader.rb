@@ -0,0 +1,105 @@
+# frozen_string_literal: true
+
+require "digest"
+require "fileutils"
+require "json"
+require "open3"
+require "rbconfig"
+require "tmpdir"
+
+module TreeStump
+  # Compiles a grammar checkout (e.g. tree-sitter-ruby) into a shared library
+  # and registers it. Built libraries are cached by the digest of their sources,
+  # so unchanged grammars are not compiled again.
+  class Loader
+    attr_reader :cache_dir
+
+    def self.default_cache_dir
+      ENV.fetch("TREE_STUMP_CACHE_DIR") do
+        File.join(ENV.fetch("XDG_CACHE_HOME") { File.join(Dir.home, ".cache") }, "tree_stump")
+      end
+    end
+
+    def initialize(cache_dir: self.class.default_cache_dir)
+      @cache_dir = cache_dir
+    end
+
+    def load(grammar_dir, name: nil, force: false)
+      src_dir = File.join(grammar_dir, "src")
+      parser_path = File.join(src_dir, "parser.c")
+      raise LanguageLoadError, "#{parser_path} is not found" unless File.exist?(parser_path)
+
+      grammar = grammar_name(src_dir, grammar_dir)
+      sources = [parser_path, *scanner_path(src_dir)]
+      library_path = File.join(cache_dir, "#{grammar}-#{digest(sources)}.#{RbConfig::CONFIG["SOEXT"]}")
+
+      compile(src_dir, sources, library_path) if force || !File.exist?(library_path)
+
+      name ||= grammar
+      TreeStump.register_lang(name, library_path, symbol: "tree_sitter_#{grammar.tr("-", "_")}")
+      name
+    end
+
+    private
+
+    def grammar_name(src_dir, grammar_dir)
+      grammar_json = File.join(src_dir, "grammar.json")
+      return JSON.parse(File.read(grammar_json)).fetch("name") if File.exist?(grammar_json)
+
+      File.basename(File.expand_path(grammar_dir)).delete_prefix("tree-sitter-")
+    end
+
+    def scanner_path(src_dir)
+      %w[scanner.c scanner.cc].map { |file| File.join(src_dir, file) }.find { |path| File.exist?(path) }
+    end
+
+    def digest(sources)
+      sha = Digest::SHA256.new
+      sources.each do |path|
+        sha << File.basename(path) << "\0" << File.binread(path)
+      end
+      sha << cc << cxx
+      sha.hexdigest[0, 16]
+    end
+
+    def compile(src_dir, sources, library_path)
+      FileUtils.mkdir_p(cache_dir)
+      tmp_path = "#{library_path}.#{Process.pid}.tmp"
+      cxx_sources, c_sources = sources.partition { |path| path.end_with?(".cc") }
+
+      Dir.mktmpdir("tree_stump") do |build_dir|
+        objects = c_sources.map do |path|
+          object = File.join(build_dir, "#{File.basename(path)}.o")
+          run!(cc, "-std=c11", "-fPIC", "-O2", "-I", src_dir, "-c", path, "-o", object)
+          object
+        end
+        linker = cxx_sources.empty? ? cc : cxx
+        run!(linker, "-shared", "-fPIC", "-O2", "-I", src_dir, *cxx_sources, *objects, "-o", tmp_path)
+      end
+
+      # Renaming keeps other processes from loading a half-written library.
+      File.rename(tmp_path, library_path)
+    ensure
+      FileUtils.rm_f(tmp_path) if tmp_path
+    end
+
+    def run!(command, *args)
+      output, status = Open3.capture2e(*command.split, *args)
+      return if status.success?
+
+      raise LanguageLoadError, "Failed to compile grammar: #{[command, *args].join(" ")}\n#{output}"
+    end
+
+    def cc
+      ENV["CC"] || RbConfig::CONFIG["CC"] || "cc"
+    end
+
+    def cxx
+      ENV["CXX"] || RbConfig::CONFIG["CXX"] || "c++"
+    end
+  end
+
+  def self.load_grammar(grammar_dir, name: nil, cache_dir: Loader.default_cache_dir, force: false)
+    Loader.new(cache_dir: cache_dir).load(grammar_dir, name: name, force: force)
+  end
+end
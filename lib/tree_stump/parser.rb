@@ -0,0 +1,48 @@
+# frozen_string_literal: true
+
+module TreeStump
+  class Parser
+    DEFAULT_CHUNK_SIZE = 64 * 1024
+
+    # Parses +io+ in chunks instead of reading it into one String, starting
+    # from its current position. Seekable IOs are repositioned when tree-sitter
+    # asks for another offset; pipes, sockets and the like keep what was read so
+    # far in memory, as tree-sitter may go back to earlier offsets.
+    def parse_io(io, old_tree = nil, chunk_size: DEFAULT_CHUNK_SIZE)
+      start = seekable_position(io)
+      return parse_buffered_io(io, old_tree, chunk_size) unless start
+
+      position = 0
+      parse_with(old_tree) do |byte_offset, _point|
+        if byte_offset != position
+          io.seek(start + byte_offset)
+          position = byte_offset
+        end
+        chunk = io.read(chunk_size)
+        position += chunk.bytesize if chunk
+        chunk
+      end
+    end
+
+    private
+
+    def parse_buffered_io(io, old_tree, chunk_size)
+      buffer = String.new(encoding: Encoding::BINARY)
+      parse_with(old_tree) do |byte_offset, _point|
+        while buffer.bytesize <= byte_offset
+          chunk = io.read(chunk_size)
+          break unless chunk
+
+          buffer << chunk
+        end
+        buffer.byteslice(byte_offset, chunk_size)
+      end
+    end
+
+    def seekable_position(io)
+      io.pos
+    rescue Errno::ESPIPE, NotImplementedError
+      nil
+    end
+  end
+end
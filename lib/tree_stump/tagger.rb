@@ -0,0 +1,29 @@
+# frozen_string_literal: true
+
+module TreeStump
+  class Tagger
+    # Builds a tagger from tags.scm (and locals.scm if present) in +queries_dir+.
+    def self.load(lang, queries_dir)
+      tags_path = File.join(queries_dir, "tags.scm")
+      raise Error, "tags.scm is not found in #{queries_dir}" unless File.exist?(tags_path)
+
+      locals_path = File.join(queries_dir, "locals.scm")
+      locals = File.read(locals_path) if File.exist?(locals_path)
+      new(lang, File.read(tags_path), locals)
+    end
+  end
+
+  class Tag
+    def definition?
+      is_definition
+    end
+
+    def reference?
+      !is_definition
+    end
+
+    def row
+      name_range.start_point.row
+    end
+  end
+end
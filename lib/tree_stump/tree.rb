@@ -0,0 +1,125 @@
+# frozen_string_literal: true
+
+module TreeStump
+  class Tree
+    MAX_SNIPPET_LENGTH = 20
+
+    def to_sexp
+      root_node.to_sexp
+    end
+
+    def has_error?
+      root_node.has_error?
+    end
+
+    # The outermost ERROR nodes in document order. Subtrees without errors are
+    # skipped.
+    def error_nodes
+      nodes = []
+      cursor = walk
+      loop do
+        node = cursor.node
+        if node.is_error?
+          nodes << node
+        elsif node.has_error? && cursor.goto_first_child
+          next
+        end
+
+        until cursor.goto_next_sibling
+          return nodes unless cursor.goto_parent
+        end
+      end
+    end
+
+    # ERROR and MISSING nodes as Diagnostics in document order. Like #error_nodes,
+    # nodes inside an ERROR node are not reported on their own.
+    def syntax_errors
+      diagnostics = []
+      cursor = walk
+      loop do
+        node = cursor.node
+        if node.is_error? || node.missing?
+          diagnostics << build_diagnostic(node)
+        elsif node.has_error? && cursor.goto_first_child
+          next
+        end
+
+        until cursor.goto_next_sibling
+          return diagnostics unless cursor.goto_parent
+        end
+      end
+    end
+
+    # Copies share tree-sitter's immutable subtrees, so they are cheap. Nodes of a
+    # copy are not equal to the nodes of the original.
+    def dup
+      copy
+    end
+
+    def clone(freeze: nil)
+      tree = copy
+      tree.freeze if freeze || (freeze.nil? && frozen?)
+      tree
+    end
+
+    private
+
+    def build_diagnostic(node)
+      kind = node.missing? ? :missing : :error
+      expected = expected_kinds(node, kind)
+      message = kind == :missing ? "Missing #{node.kind}" : unexpected_message(node)
+      message += ", expected #{expected.join(", ")}" if kind == :error && !expected.empty?
+      Diagnostic.new(kind: kind, node: node, range: node.range, expected: expected, message: message)
+    end
+
+    def unexpected_message(node)
+      text = source&.byteslice(node.byte_range)&.scrub&.lines&.first&.strip
+      return "Syntax error" if text.nil? || text.empty?
+
+      text = "#{text[0, MAX_SNIPPET_LENGTH]}..." if text.length > MAX_SNIPPET_LENGTH
+      "Unexpected #{text.inspect}"
+    end
+
+    # As tree-sitter recommends: the state of the first leaf of an ERROR node,
+    # and the state after the previous non-extra leaf of a MISSING node.
+    def expected_kinds(node, kind)
+      state =
+        if kind == :error
+          leaf = node
+          leaf = leaf.child(0) while leaf.child_count > 0
+          leaf.parse_state
+        else
+          previous = previous_leaf(node)
+          previous ? previous.next_parse_state : node.parse_state
+        end
+
+      lang = language
+      iterator = lang.lookahead_iterator(state)
+      return [] unless iterator
+
+      kinds = []
+      while (id = iterator.next)
+        # 0 is the end of input and 0xFFFF the ERROR symbol.
+        next if id.zero? || id == 0xFFFF || !lang.node_kind_is_visible(id)
+
+        kinds << iterator.current_symbol_name
+      end
+      kinds.uniq
+    end
+
+    def previous_leaf(node)
+      loop do
+        sibling = node.prev_sibling
+        while sibling.nil?
+          node = node.parent
+          return nil unless node
+
+          sibling = node.prev_sibling
+        end
+        node = sibling
+        node = node.child(node.child_count - 1) while node.child_count > 0
+        return node unless node.is_extra?
+      end
+    end
+  end
+end
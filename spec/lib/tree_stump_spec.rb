@@ -1,4 +1,7 @@
 require "tempfile"
+require "tmpdir"
+require "fileutils"
+require "stringio"
 
 RSpec.describe TreeStump do
   before(:all) do
@@ -9,6 +12,152 @@ RSpec.describe TreeStump do
     expect(TreeStump.available_langs).to include("ruby")
   end
 
+  it "can register lang with an explicit symbol name" do
+    TreeStump.register_lang("rb", tree_sitter_ruby_path, symbol: "tree_sitter_ruby")
+    expect(TreeStump.available_langs).to include("ruby", "rb")
+
+    parser = TreeStump::Parser.new
+    parser.set_language("rb")
+    expect(parser.parse("1 + 1").root_node.kind).to eq("program")
+  end
+
+  describe ".language" do
+    let(:language) { TreeStump.language("ruby") }
+
+    it "returns registered language" do
+      expect(language).to be_a(TreeStump::Language)
+      expect(language.version).to be_between(13, 14)
+    end
+
+    it "lists node kinds, fields and supertypes" do
+      program = language.node_kinds.find { |kind| kind.name == "program" }
+      expect(program).to be_named
+      expect(program).to be_visible
+      expect(language.node_kinds.size).to be <= language.node_kind_count
+      expect(language.fields).to include("name", "body", "receiver")
+      expect(language.fields.size).to eq(language.field_count)
+      expect(language.supertypes).to include("_expression", "_statement")
+    end
+
+    it "looks up node kinds and fields" do
+      id = language.id_for_node_kind("program", true)
+      expect(language.node_kind_for_id(id)).to eq("program")
+      expect(language.node_kind_is_named(id)).to eq(true)
+      expect(language.node_kind_is_visible(id)).to eq(true)
+
+      field_id = language.field_id_for_name("name")
+      expect(language.field_name_for_id(field_id)).to eq("name")
+      expect(language.field_id_for_name("no_such_field")).to be_nil
+    end
+
+    it "iterates lookahead symbols" do
+      iterator = language.lookahead_iterator(1)
+      symbols = []
+      symbols << iterator.current_symbol_name while iterator.next
+      expect(symbols).to include("class", "def")
+      expect(language.parse_state_count).to be > 1
+    end
+
+    it "can be passed to Parser#set_language" do
+      parser = TreeStump::Parser.new
+      expect(parser.language).to be_nil
+
+      parser.set_language(language)
+      expect(parser.language.version).to eq(language.version)
+      expect(parser.parse("1 + 1").root_node.kind).to eq("program")
+    end
+
+    it "raises Error for unknown language" do
+      expect { TreeStump.language("unknown") }.to raise_error(TreeStump::Error)
+    end
+  end
+
+  describe ".unregister_lang" do
+    before { TreeStump.register_lang("ruby_tmp", tree_sitter_ruby_path, symbol: "tree_sitter_ruby") }
+
+    it "removes language from the registry" do
+      expect(TreeStump.lang_registered?("ruby_tmp")).to eq(true)
+      expect(TreeStump.unregister_lang("ruby_tmp")).to eq(true)
+      expect(TreeStump.lang_registered?("ruby_tmp")).to eq(false)
+      expect(TreeStump.available_langs).not_to include("ruby_tmp")
+      expect(TreeStump.unregister_lang("ruby_tmp")).to eq(false)
+    end
+
+    it "keeps trees parsed before unregistering usable" do
+      parser = TreeStump::Parser.new
+      parser.set_language("ruby_tmp")
+      tree = parser.parse("def foo; end")
+      TreeStump.unregister_lang("ruby_tmp")
+
+      expect(tree.root_node.child(0).kind).to eq("method")
+      expect { parser.set_language("ruby_tmp") }.to raise_error(TreeStump::Error)
+    end
+
+    it "keeps lookahead iterators of a tree's language usable" do
+      Dir.mktmpdir do |dir|
+        # A copy of the library, so that unregistering can actually unload it.
+        library_path = File.join(dir, File.basename(tree_sitter_ruby_path))
+        FileUtils.cp(tree_sitter_ruby_path, library_path)
+        TreeStump.register_lang("ruby_copy", library_path, symbol: "tree_sitter_ruby")
+
+        parser = TreeStump::Parser.new
+        parser.set_language("ruby_copy")
+        tree = parser.parse("def foo; end")
+        iterator = tree.language.lookahead_iterator(1)
+        parser = tree = nil
+        TreeStump.unregister_lang("ruby_copy")
+        GC.start
+
+        expect(iterator.next).to be_a(Integer)
+        expect(iterator.current_symbol_name).to be_a(String)
+      end
+    end
+  end
+
+  describe ".load_grammar" do
+    # Shared by the examples so that parser.c is only compiled once.
+    before(:all) { @cache_dir = Dir.mktmpdir }
+    after(:all) { FileUtils.rm_rf(@cache_dir) }
+
+    let(:cache_dir) { @cache_dir }
+    let(:grammar_dir) { File.join(project_root, "tree-sitter-ruby") }
+
+    it "compiles grammar sources and registers the language" do
+      expect(TreeStump.load_grammar(grammar_dir, name: "ruby_compiled", cache_dir: cache_dir)).to eq("ruby_compiled")
+
+      parser = TreeStump::Parser.new
+      parser.set_language("ruby_compiled")
+      expect(parser.parse("1 + 1").root_node.kind).to eq("program")
+    end
+
+    it "skips compilation when sources are unchanged" do
+      TreeStump.load_grammar(grammar_dir, name: "ruby_cached", cache_dir: cache_dir)
+      library, = Dir.glob(File.join(cache_dir, "*"))
+      mtime = File.mtime(library)
+
+      TreeStump.load_grammar(grammar_dir, name: "ruby_cached", cache_dir: cache_dir)
+      expect(Dir.glob(File.join(cache_dir, "*"))).to eq([library])
+      expect(File.mtime(library)).to eq(mtime)
+    end
+
+    it "raises LanguageLoadError without parser.c" do
+      Dir.mktmpdir do |empty_dir|
+        expect { TreeStump.load_grammar(empty_dir, cache_dir: cache_dir) }.to raise_error(TreeStump::LanguageLoadError)
+      end
+    end
+  end
+
+  it "raises LanguageLoadError when the library cannot be loaded" do
+    expect { TreeStump.register_lang("missing", "/path/to/missing.so") }
+      .to raise_error(TreeStump::LanguageLoadError, %r{/path/to/missing\.so \(expected to export tree_sitter_missing\)})
+  end
+
+  it "raises SymbolNotFoundError when the library lacks the language function" do
+    expect { TreeStump.register_lang("no_such_lang", tree_sitter_ruby_path) }
+      .to raise_error(TreeStump::SymbolNotFoundError, /tree_sitter_no_such_lang/)
+    expect(TreeStump.available_langs).not_to include("no_such_lang")
+  end
+
   let(:parser) do
     TreeStump::Parser.new.tap do |p|
       p.set_language("ruby")
@@ -39,6 +188,168 @@ RSpec.describe TreeStump do
     expect(root_node).to be_a(TreeStump::Node)
   end
 
+  it "can parse in multiple threads" do
+    expected = parser.parse(source).root_node.to_sexp
+    sexps = 4.times.map do
+      Thread.new do
+        TreeStump::Parser.new.tap { |p| p.set_language("ruby") }.parse(source * 100).root_node.to_sexp
+      end
+    end.map(&:value)
+    expect(sexps).to all(start_with(expected.delete_suffix(")")))
+  end
+
+  describe "cancellation" do
+    let(:large_source) { source * 20_000 }
+
+    it "raises ParseCancelled when cancelled before parsing" do
+      parser.cancel!
+      expect(parser.cancellation_flag).to eq(1)
+      expect { parser.parse(large_source) }.to raise_error(TreeStump::ParseCancelled)
+
+      expect(parser.cancellation_flag).to eq(0)
+      expect(parser.parse(source).root_node.kind).to eq("program")
+    end
+
+    it "can be cancelled from another thread" do
+      thread = Thread.new { parser.parse(large_source * 10) }
+      sleep 0.01
+      parser.cancel!
+      expect { thread.value }.to raise_error(TreeStump::ParseCancelled)
+    end
+
+    it "cancels a short parse and then parses normally" do
+      parser.cancel!
+      expect { parser.parse("1") }.to raise_error(TreeStump::ParseCancelled)
+      expect(parser.parse("1").root_node.kind).to eq("program")
+      expect(parser.parse("2").root_node.kind).to eq("program")
+      expect(parser.cancellation_flag).to eq(0)
+    end
+
+    it "raises ParseTimeout when the timeout is exceeded" do
+      parser.set_timeout_micros(1)
+      expect { parser.parse(large_source) }.to raise_error(TreeStump::ParseTimeout)
+    end
+
+    it "raises Error rather than ParseTimeout without a language" do
+      parser = TreeStump::Parser.new
+      parser.set_timeout_micros(1_000)
+      expect { parser.parse(source) }.to raise_error(TreeStump::Error, /no language/)
+    end
+
+    it "resumes parsing after an interrupt that raises nothing" do
+      thread = Thread.new { parser.parse(large_source * 10) }
+      sleep 0.01
+      begin
+        thread.wakeup
+      rescue ThreadError
+        # The parse already finished.
+      end
+      expect(thread.value.root_node.kind).to eq("program")
+    end
+
+    it "honours Thread#raise during parsing" do
+      thread = Thread.new { parser.parse(large_source * 10) }
+      sleep 0.01
+      thread.raise(RuntimeError, "stop")
+      expect { thread.value }.to raise_error(RuntimeError, "stop")
+      expect(parser.parse(source).root_node.kind).to eq("program")
+    end
+  end
+
+  describe "Parser#parse_with" do
+    it "parses chunks returned from the block" do
+      offsets = []
+      tree = parser.parse_with do |byte_offset, point|
+        expect(point).to be_a(TreeStump::Point)
+        offsets << byte_offset
+        source.byteslice(byte_offset, 8)
+      end
+      expect(tree.root_node.to_sexp).to eq(parser.parse(source).root_node.to_sexp)
+      expect(offsets.size).to be > 1
+    end
+
+    it "reparses with old tree" do
+      old_tree = parser.parse(source)
+      tree = parser.parse_with(old_tree) { |byte_offset, _| source.byteslice(byte_offset..) }
+      expect(tree.root_node.to_sexp).to eq(old_tree.root_node.to_sexp)
+    end
+
+    it "raises errors from the block" do
+      expect { parser.parse_with { raise ArgumentError, "broken" } }.to raise_error(ArgumentError, "broken")
+    end
+  end
+
+  describe "encoding" do
+    let(:source) { %(x = "é\u{1F600}"; y = 1\n) }
+
+    it "parses UTF-16 source" do
+      tree = parser.parse(source, encoding: :utf16)
+      expect(tree.root_node.to_sexp).to eq(parser.parse(source).root_node.to_sexp)
+
+      y = tree.root_node.child(2)
+      expect(y.start_byte).to eq(source.encode("UTF-16LE").index("y".encode("UTF-16LE")) * 2)
+    end
+
+    it "raises ArgumentError for unsupported encoding" do
+      expect { parser.parse(source, encoding: :sjis) }.to raise_error(ArgumentError)
+    end
+
+    it "converts byte columns to UTF-16 and character columns" do
+      y = parser.parse(source).root_node.child(2)
+      expect(y.start_position.column).to eq(source.b.index("y"))
+      expect(y.start_position_utf16(source)).to eq(TreeStump::Point.new(0, 11))
+      expect(y.start_position_chars(source)).to eq(TreeStump::Point.new(0, 10))
+      expect(y.end_position_utf16(source).column).to eq(y.start_position_utf16(source).column + 5)
+      expect(y.start_position.utf16_column(source)).to eq(11)
+      expect(y.start_position.char_column(source)).to eq(10)
+    end
+
+    it "converts UTF-16 and character columns to byte columns" do
+      byte_column = source.b.index("y")
+      expect(TreeStump::Point.from_utf16_column(source, 0, 11)).to eq(TreeStump::Point.new(0, byte_column))
+      expect(TreeStump::Point.from_char_column(source, 0, 10)).to eq(TreeStump::Point.new(0, byte_column))
+      expect(TreeStump::Point.from_utf16_column(source, 0, 100).column).to eq(source.bytesize - 1)
+    end
+
+    it "raises Error for positions outside the source" do
+      expect { TreeStump::Point.new(5, 0).utf16_column(source) }.to raise_error(TreeStump::Error)
+    end
+
+    it "raises Error when converting columns of a UTF-16 tree" do
+      y = parser.parse(source, encoding: :utf16).root_node.child(2)
+      expect { y.start_position_utf16(source) }.to raise_error(TreeStump::Error)
+      expect { y.end_position_chars(source) }.to raise_error(TreeStump::Error)
+    end
+  end
+
+  it "can parse IO" do
+    tree = StringIO.open(source) { |io| parser.parse_io(io, chunk_size: 16) }
+    expect(tree.root_node.to_sexp).to eq(parser.parse(source).root_node.to_sexp)
+  end
+
+  it "can parse IO that is not seekable" do
+    reader, writer = IO.pipe
+    writer.write(source)
+    writer.close
+
+    tree = parser.parse_io(reader, chunk_size: 16)
+    expect(tree.root_node.to_sexp).to eq(parser.parse(source).root_node.to_sexp)
+  ensure
+    reader&.close
+  end
+
+  it "can parse broken source from IO that is not seekable" do
+    broken = "class Foo\n  def bar(\n  end\nend\nx = )\n" * 50
+    reader, writer = IO.pipe
+    writer.write(broken)
+    writer.close
+
+    tree = parser.parse_io(reader, chunk_size: 4)
+    expect(tree.root_node.to_sexp).to eq(parser.parse(broken).root_node.to_sexp)
+  ensure
+    reader&.close
+  end
+
   it "can print_dot_graph" do
     tree = parser.parse(source)
 
@@ -49,6 +360,376 @@ RSpec.describe TreeStump do
     end
   end
 
+  describe "included ranges" do
+    # Each Ruby region ends its statement, as the regions are parsed as if adjacent.
+    let(:template) { "<p><% name = 1; %></p><% puts name; %>" }
+    let(:ranges) do
+      template.to_enum(:scan, /<%(.*?)%>/).map do
+        start_byte, end_byte = Regexp.last_match.offset(1)
+        TreeStump::Range.new(start_byte, end_byte, TreeStump::Point.new(0, start_byte), TreeStump::Point.new(0, end_byte))
+      end
+    end
+
+    it "parses only the included ranges" do
+      parser.included_ranges = ranges
+      expect(parser.included_ranges).to eq(ranges)
+
+      tree = parser.parse(template)
+      expect(tree.included_ranges).to eq(ranges)
+      expect(tree.root_node.has_error?).to be false
+      expect(tree.root_node.named_child(0).kind).to eq("assignment")
+      expect(tree.root_node.named_child(1).kind).to eq("call")
+
+      parser.set_included_ranges([])
+      expect(parser.parse(template).root_node.has_error?).to be true
+    end
+
+    it "accepts ranges of another tree's nodes" do
+      node = parser.parse("x = 1\ny = 2\n").root_node.named_child(1)
+      parser.included_ranges = [node.range]
+      expect(parser.included_ranges).to eq([node.range])
+    end
+
+    it "rejects overlapping or unordered ranges" do
+      expect { parser.included_ranges = ranges.reverse }
+        .to raise_error(TreeStump::Error, /index 1 overlaps or precedes the previous range/)
+    end
+
+    it "rejects ranges that end before they start" do
+      point = TreeStump::Point.new(0, 0)
+      expect { parser.included_ranges = [TreeStump::Range.new(5, 2, point, point)] }
+        .to raise_error(TreeStump::Error, /index 0 ends before it starts/)
+    end
+  end
+
+  describe "Parser#logger" do
+    it "receives parse and lex messages" do
+      messages = []
+      parser.logger = ->(type, message) { messages << [type, message] }
+      expect(parser.logger).to be_a(Proc)
+
+      parser.parse(source)
+      expect(messages.map(&:first).uniq).to contain_exactly(:parse, :lex)
+      expect(messages.map(&:last)).to all(be_a(String))
+
+      parser.logger = nil
+      messages.clear
+      parser.parse(source)
+      expect(messages).to be_empty
+    end
+
+    it "raises errors from the logger" do
+      parser.logger = ->(_type, _message) { raise ArgumentError, "broken" }
+      expect { parser.parse(source) }.to raise_error(ArgumentError, "broken")
+
+      parser.logger = nil
+      expect(parser.parse(source).root_node.kind).to eq("program")
+    end
+
+    it "stops parsing once the logger raises" do
+      large_source = source * 100
+      reads = 0
+      parser.logger = ->(_type, _message) { raise ArgumentError, "broken" }
+      expect {
+        parser.parse_with do |byte_offset, _point|
+          reads += 1
+          large_source.byteslice(byte_offset, 1)
+        end
+      }.to raise_error(ArgumentError, "broken")
+      expect(reads).to be < large_source.bytesize
+
+      parser.logger = nil
+      expect(parser.cancellation_flag).to eq(0)
+      expect(parser.parse(source).root_node.kind).to eq("program")
+    end
+  end
+
+  it "can print_dot_graphs while parsing" do
+    Tempfile.create("print_dot_graphs") do |f|
+      parser.print_dot_graphs(f)
+      parser.parse(source)
+      parser.stop_printing_dot_graphs
+      expect(File.read(f)).to include("digraph")
+    end
+  end
+
+  describe "TreeStump::Tree" do
+    let(:tree) { parser.parse(source) }
+    let(:new_source) { source.sub("def hello", "def hello_world") }
+    let(:edit) do
+      start_byte = source.index("hello")
+      TreeStump::InputEdit.new(
+        start_byte,
+        start_byte + "hello".bytesize,
+        start_byte + "hello_world".bytesize,
+        TreeStump::Point.new(1, 6),
+        TreeStump::Point.new(1, 11),
+        TreeStump::Point.new(1, 17),
+      )
+    end
+
+    it "#source returns the frozen source the tree was parsed from" do
+      expect(tree.source).to eq(source)
+      expect(tree.source).to be_frozen
+      expect(tree.source).to equal(tree.source)
+      expect(parser.parse(source, encoding: :utf16).source).to be_nil
+    end
+
+    it "does not keep the source with keep_source: false" do
+      tree = parser.parse(source, keep_source: false)
+      expect(tree.source).to be_nil
+      expect { tree.root_node.text }.to raise_error(TreeStump::Error, /without its source/)
+      expect(tree.root_node.child(0).utf8_text(source)).to start_with("class Hoge")
+    end
+
+    describe "#copy" do
+      it "returns an independent tree" do
+        copy = tree.copy
+        expect(copy).to be_a(TreeStump::Tree)
+        expect(copy.root_node.to_sexp).to eq(tree.root_node.to_sexp)
+        expect(copy.source).to eq(source)
+
+        copy.edit(edit)
+        expect(copy.root_node.has_changes?).to be_truthy
+        expect(tree.root_node.has_changes?).to be_falsey
+      end
+
+      it "is used by #dup and #clone" do
+        expect(tree.dup.root_node.to_sexp).to eq(tree.root_node.to_sexp)
+        expect(tree.clone.root_node.to_sexp).to eq(tree.root_node.to_sexp)
+        expect(tree.clone(freeze: true)).to be_frozen
+      end
+
+      it "gives nodes that are not equal to the original's" do
+        copy = tree.copy
+        expect(copy.root_node).not_to eq(tree.root_node)
+        expect(copy.root_node).to eq(copy.root_node)
+        expect(tree.root_node).to eq(tree.root_node)
+        expect(tree.root_node.hash).to eq(tree.root_node.hash)
+        expect([tree.root_node, copy.root_node].uniq.size).to eq(2)
+      end
+    end
+
+    describe "#root_node_with_offset" do
+      let(:offset_point) { TreeStump::Point.new(2, 4) }
+
+      it "shifts byte offsets and positions" do
+        root = tree.root_node_with_offset(10, offset_point)
+        expect(root.kind).to eq("program")
+        expect(root.start_byte).to eq(10)
+        expect(root.end_byte).to eq(tree.root_node.end_byte + 10)
+        expect(root.start_position).to eq(TreeStump::Point.new(2, 4))
+      end
+
+      it "shifts columns only on the first row" do
+        root = tree.root_node_with_offset(10, offset_point)
+        class_name = root.child(0).child(1)
+        method_node = root.child(0).child(2).child(0)
+        expect(class_name.start_position).to eq(TreeStump::Point.new(2, 10))
+        expect(method_node.start_position).to eq(TreeStump::Point.new(3, 2))
+        expect(method_node.start_byte).to eq(source.index("def hello") + 10)
+      end
+
+      it "keeps the structure of the tree" do
+        expect(tree.root_node_with_offset(10, offset_point).to_sexp).to eq(tree.to_sexp)
+      end
+
+      it "returns equal nodes on each call" do
+        root = tree.root_node_with_offset(10, offset_point)
+        expect(tree.root_node_with_offset(10, offset_point)).to eq(root)
+        expect(tree.root_node_with_offset(10, offset_point).hash).to eq(root.hash)
+      end
+
+      it "needs the host source for text" do
+        host_source = "#" * 10 + source
+        root = tree.root_node_with_offset(10, offset_point)
+        query = parser.build_query("(class name: (constant) @name)")
+
+        expect { root.text }.to raise_error(TreeStump::Error, /without its source/)
+        expect { TreeStump::QueryCursor.new.matches(query, root).to_a }.to raise_error(TreeStump::Error)
+        expect(root.child(0).utf8_text(host_source)).to start_with("class Hoge")
+        names = TreeStump::QueryCursor.new.matches(query, root, host_source).map { |m| m.captures[0].node.utf8_text(host_source) }
+        expect(names).to eq(%w[Hoge])
+      end
+    end
+
+    describe "#text and #byte_size" do
+      it "return the source and its size" do
+        expect(tree.text).to eq(source)
+        expect(tree.text).to be_frozen
+        expect(parser.parse("x = 1").byte_size).to eq(5)
+      end
+
+      it "raise Error for text when the tree has no source" do
+        tree = parser.parse(source, keep_source: false)
+        expect { tree.text }.to raise_error(TreeStump::Error, /without its source/)
+        expect(tree.byte_size).to eq(tree.root_node.end_byte)
+      end
+    end
+
+    describe "#to_sexp" do
+      it "returns the sexp of the root node" do
+        expect(tree.to_sexp).to eq(tree.root_node.to_sexp)
+        expect(tree.to_sexp).to start_with("(program (class name: (constant)")
+      end
+    end
+
+    describe "#has_error? and #error_nodes" do
+      let(:broken_source) { "class Foo\n  def bar(\n  end\nend\nx = )\n" }
+      let(:broken_tree) { parser.parse(broken_source) }
+
+      it "reports no errors for a valid tree" do
+        expect(tree.has_error?).to be false
+        expect(tree.error_nodes).to eq([])
+      end
+
+      it "reports errors of a broken tree" do
+        expect(broken_tree.has_error?).to be true
+        expect(broken_tree.error_nodes).not_to be_empty
+        expect(broken_tree.error_nodes).to all(be_a(TreeStump::Node))
+        expect(broken_tree.error_nodes.map(&:is_error?)).to all(be true)
+      end
+
+      it "returns outermost error nodes in document order" do
+        nodes = broken_tree.error_nodes
+        expect(nodes.map(&:start_byte)).to eq(nodes.map(&:start_byte).sort)
+        nodes.each do |node|
+          ancestor = node.parent
+          while ancestor
+            expect(ancestor.is_error?).to be false
+            ancestor = ancestor.parent
+          end
+        end
+      end
+
+      it "returns nodes of the tree" do
+        expect(broken_tree.error_nodes.first.text).to eq(broken_source.byteslice(broken_tree.error_nodes.first.byte_range))
+      end
+    end
+
+    describe "#syntax_errors" do
+      it "returns no diagnostics for a valid tree" do
+        expect(tree.syntax_errors).to eq([])
+      end
+
+      it "reports MISSING nodes" do
+        broken = parser.parse("[1, 2\n")
+        diagnostic = broken.syntax_errors.find(&:missing?)
+        expect(diagnostic.kind).to eq(:missing)
+        expect(diagnostic.node).to be_missing
+        expect(diagnostic.message).to eq("Missing ]")
+        expect(diagnostic.range).to be_a(TreeStump::Range)
+        expect(diagnostic.expected).to include("]")
+        expect(diagnostic.to_s).to start_with("#{diagnostic.range.start_point.row + 1}:")
+      end
+
+      it "reports ERROR nodes with expected kinds" do
+        broken_source = "x = 1\ny = )\n"
+        diagnostics = parser.parse(broken_source).syntax_errors
+        expect(diagnostics).not_to be_empty
+
+        diagnostic = diagnostics.find(&:error?)
+        expect(diagnostic.node).to be_is_error
+        expect(diagnostic.range).to eq(diagnostic.node.range)
+        expect(diagnostic.expected).to all(be_a(String))
+        expect(diagnostic.expected).not_to include("ERROR")
+        expect(diagnostic.message).to start_with("Unexpected")
+      end
+
+      it "uses a generic message without the source" do
+        broken_source = "x = 1\ny = )\n"
+        tree = parser.parse_with { |byte_offset, _| broken_source.byteslice(byte_offset..) }
+        expect(tree.syntax_errors.find(&:error?).message).to start_with("Syntax error")
+      end
+    end
+
+    it "#source is dropped by #edit" do
+      tree.edit(edit)
+      expect(tree.source).to be_nil
+    end
+
+    describe "#edit" do
+      it "marks edited nodes as changed" do
+        expect(tree.root_node.has_changes?).to be_falsey
+        tree.edit(edit)
+        expect(tree.root_node.has_changes?).to be_truthy
+        expect(tree.root_node.end_byte).to eq(new_source.bytesize)
+      end
+
+      it "does not affect nodes taken before the edit" do
+        root_node = tree.root_node
+        tree.edit(edit)
+        expect(root_node.has_changes?).to be_falsey
+        expect(root_node.end_byte).to eq(source.bytesize)
+      end
+    end
+
+    describe "incremental parsing" do
+      it "reuses the old tree" do
+        tree.edit(edit)
+        new_tree = parser.parse(new_source, tree)
+        method_name = new_tree.root_node.child(0).child(2).child(0).child_by_field_name("name")
+        expect(method_name.utf8_text(new_source)).to eq("hello_world")
+        expect(new_tree.root_node.has_changes?).to be_falsey
+      end
+
+      it "accepts nil as the old tree" do
+        expect(parser.parse(source, nil)).to be_a(TreeStump::Tree)
+      end
+    end
+
+    describe "#changed_ranges" do
+      let(:source) do
+        <<~RUBY
+        a = 1
+        b = 2
+        RUBY
+      end
+      let(:new_source) do
+        <<~RUBY
+        a = 1
+        b = [2]
+        RUBY
+      end
+      let(:edit) do
+        TreeStump::InputEdit.new(
+          10, 11, 13,
+          TreeStump::Point.new(1, 4),
+          TreeStump::Point.new(1, 5),
+          TreeStump::Point.new(1, 7),
+        )
+      end
+
+      it "returns ranges whose syntactic structure has changed" do
+        tree.edit(edit)
+        new_tree = parser.parse(new_source, tree)
+
+        ranges = tree.changed_ranges(new_tree).to_a
+        expect(ranges).not_to be_empty
+        expect(ranges).to all(be_a(TreeStump::Range))
+        expect(ranges.first.start_point.row).to eq(1)
+        expect(ranges.first.start_byte).to be <= 10
+        expect(ranges.last.end_byte).to be >= 13
+      end
+
+      it "returns no ranges for an unchanged source" do
+        new_tree = parser.parse(source, tree)
+        expect(tree.changed_ranges(new_tree).to_a).to be_empty
+      end
+
+      it "yields each range when a block is given" do
+        tree.edit(edit)
+        new_tree = parser.parse(new_source, tree)
+
+        result = []
+        tree.changed_ranges(new_tree) do |range|
+          result << range
+        end
+        expect(result).to eq(tree.changed_ranges(new_tree).to_a)
+      end
+    end
+  end
+
   describe "TreeStump::Node" do
     let(:node) do
       parser.parse(source).root_node
@@ -64,6 +745,22 @@ RSpec.describe TreeStump do
       end
     end
 
+    describe "#id" do
+      it "returns the same id for the same node" do
+        expect(node.child(0).id).to eq(node.child(0).id)
+        expect(node.child(0).id).not_to eq(node.child(1).id)
+      end
+    end
+
+    describe "#== and #eql?" do
+      it "compares nodes of the same tree" do
+        expect(node.child(0)).to eq(node.child(0))
+        expect(node.child(0)).to eql(node.child(0))
+        expect(node.child(0)).not_to eq(node.child(1))
+        expect(node.child(0).hash).to eq(node.child(0).hash)
+      end
+    end
+
     describe "#kind" do
       it "returns the node's kind" do
         expect(node.kind).to eq("program")
@@ -120,6 +817,16 @@ RSpec.describe TreeStump do
       end
     end
 
+    describe "#parse_state and #next_parse_state" do
+      it "returns the states before and after the node" do
+        keyword = node.child(0).child(0)
+        language = node.language
+        expect(keyword.parse_state).to be_a(Integer)
+        expect(keyword.next_parse_state).to eq(language.next_state(keyword.parse_state, keyword.grammar_id))
+        expect(keyword.next_parse_state).not_to eq(keyword.parse_state)
+      end
+    end
+
     describe "#start_byte" do
       it "returns the node's start byte" do
         expect(node.start_byte).to eq(0)
@@ -156,6 +863,14 @@ RSpec.describe TreeStump do
       end
     end
 
+    describe "#end_position_chars" do
+      it "returns the end position with a character column" do
+        string = parser.parse(%(x = "é"\n)).root_node.child(0).child(2)
+        expect(string.end_position).to eq(TreeStump::Point.new(0, 8))
+        expect(string.end_position_chars(%(x = "é"\n))).to eq(TreeStump::Point.new(0, 7))
+      end
+    end
+
     describe "#child" do
       it "returns the node's child" do
         expect(node.child(0)).to be_a(TreeStump::Node)
@@ -204,6 +919,52 @@ RSpec.describe TreeStump do
       end
     end
 
+    describe "#field_name_for_child" do
+      it "returns the field name of the child at the index" do
+        expect(node.child(0).field_name_for_child(0)).to be_nil
+        expect(node.child(0).field_name_for_child(1)).to eq("name")
+      end
+    end
+
+    context "with a repeated field" do
+      let(:source) { "case x\nwhen 1, 2 then :a\nend\n" }
+      let(:when_node) { node.child(0).children.find { |child| child.kind == "when" } }
+
+      describe "#children_by_field_name" do
+        it "returns every child of the field" do
+          expect(when_node.child_by_field_name("pattern").start_byte).to eq(source.index("1"))
+          patterns = when_node.children_by_field_name("pattern")
+          expect(patterns.map(&:kind)).to eq(%w[pattern pattern])
+          expect(patterns.map { |pattern| pattern.text }).to eq(%w[1 2])
+          expect(when_node.children_by_field_name("pattern").to_a.size).to eq(2)
+          expect(when_node.children_by_field_name("unknown").to_a).to eq([])
+        end
+      end
+
+      describe "#children_by_field_id" do
+        it "returns every child of the field" do
+          field_id = node.language.field_id_for_name("pattern")
+          expect(when_node.children_by_field_id(field_id).map(&:text)).to eq(%w[1 2])
+          expect { when_node.children_by_field_id(0) }.to raise_error(TreeStump::Error, /non-zero/)
+        end
+      end
+
+      describe "#children_by_field_name_with_cursor" do
+        it "returns every child of the field" do
+          patterns = when_node.children_by_field_name_with_cursor("pattern", when_node.walk)
+          expect(patterns.map(&:text)).to eq(%w[1 2])
+        end
+      end
+
+      describe "#children_by_field_id_with_cursor" do
+        it "returns every child of the field" do
+          field_id = node.language.field_id_for_name("pattern")
+          patterns = when_node.children_by_field_id_with_cursor(field_id, when_node.walk)
+          expect(patterns.map(&:text)).to eq(%w[1 2])
+        end
+      end
+    end
+
     describe "#children" do
       it "yields the node's children with cursor" do
         result = []
@@ -244,6 +1005,44 @@ RSpec.describe TreeStump do
       end
     end
 
+    describe "#named_children" do
+      it "returns the node's named children" do
+        class_node = node.child(0)
+        expect(class_node.named_children.map(&:kind)).to eq(%w[constant body_statement])
+        expect(class_node.named_children.to_a.size).to eq(class_node.named_child_count)
+
+        result = []
+        class_node.named_children { |child| result << child.kind }
+        expect(result).to eq(%w[constant body_statement])
+      end
+    end
+
+    describe "#named_children_with_cursor" do
+      it "returns the node's named children" do
+        class_node = node.child(0)
+        expect(class_node.named_children_with_cursor(class_node.walk).map(&:kind)).to eq(%w[constant body_statement])
+      end
+    end
+
+    describe "#first_child_for_byte" do
+      it "returns the first child containing or after the byte" do
+        expect(node.first_child_for_byte(0)).to eq(node.child(0))
+        expect(node.first_child_for_byte(source.index("Hoge.new"))).to eq(node.child(1))
+        expect(node.child(0).first_child_for_byte(0).kind).to eq("class")
+        expect(node.first_child_for_byte(source.bytesize + 1)).to be_nil
+        expect(node.first_child_for_byte(2**32)).to be_nil
+      end
+    end
+
+    describe "#first_named_child_for_byte" do
+      it "returns the first named child containing or after the byte" do
+        expect(node.child(0).first_named_child_for_byte(0)).to eq(node.child(0).child(1))
+        expect(node.child(0).first_named_child_for_byte(0).kind).to eq("constant")
+        expect(node.first_named_child_for_byte(source.bytesize + 1)).to be_nil
+        expect(node.first_named_child_for_byte(2**32)).to be_nil
+      end
+    end
+
     describe "#parent" do
       it "returns the node's parent" do
         expect(node.child(0).parent).to eq(node)
@@ -314,6 +1113,12 @@ RSpec.describe TreeStump do
         method2_node = node.child(0).child(2).child(1)
         expect(node.named_descendant_for_point_range([5, 2], [8, 5])).to eq(method2_node)
       end
+
+      it "skips anonymous nodes" do
+        method2_node = node.child(0).child(2).child(1)
+        expect(node.descendant_for_point_range([5, 2], [5, 5]).kind).to eq("def")
+        expect(node.named_descendant_for_point_range([5, 2], [5, 5])).to eq(method2_node)
+      end
     end
 
     describe "#to_sexp" do
@@ -322,10 +1127,60 @@ RSpec.describe TreeStump do
       end
     end
 
+    describe "#missing?" do
+      it "returns whether tree-sitter inserted the node" do
+        expect(node.missing?).to be false
+        expect(node.is_missing?).to be false
+
+        missing = parser.parse("[1, 2\n").syntax_errors.find(&:missing?).node
+        expect(missing.missing?).to be true
+        expect(missing.byte_range.size).to eq(0)
+      end
+    end
+
     describe "#utf8_text" do
       it "returns the node's utf8 text" do
         expect(node.child(0).child(1).utf8_text(source)).to eq("Hoge")
       end
+
+      it "raises Error when the node is outside of the source" do
+        expect { node.child(0).child(1).utf8_text("class") }
+          .to raise_error(TreeStump::Error, /outside of the source \(5 bytes\)/)
+      end
+
+      it "returns text in the encoding of the source" do
+        latin1 = "# caf\xE9\nx = 1\n".dup.force_encoding(Encoding::ISO_8859_1)
+        tree = parser.parse(latin1)
+        comment = tree.root_node.child(0)
+        expect(comment.kind).to eq("comment")
+        expect(comment.utf8_text(latin1)).to eq(latin1.byteslice(0, 6))
+        expect(comment.utf8_text(latin1).encoding).to eq(Encoding::ISO_8859_1)
+        expect(comment.text.encoding).to eq(Encoding::ISO_8859_1)
+        expect(tree.root_node.child(1).utf8_text(latin1)).to eq("x = 1".encode(Encoding::ISO_8859_1))
+      end
+    end
+
+    describe "#byte_slice" do
+      it "returns the node's bytes as a binary String" do
+        text = node.child(0).child(1).byte_slice(source)
+        expect(text).to eq("Hoge".b)
+        expect(text.encoding).to eq(Encoding::BINARY)
+      end
+
+      it "raises Error when the node is outside of the source" do
+        expect { node.child(0).child(1).byte_slice("") }.to raise_error(TreeStump::Error)
+      end
+    end
+
+    describe "#text" do
+      it "returns the node's text from the tree's source" do
+        expect(node.child(0).child(1).text).to eq("Hoge")
+      end
+
+      it "raises Error when the tree has no source" do
+        tree = parser.parse_with { |byte_offset, _| source.byteslice(byte_offset..) }
+        expect { tree.root_node.child(0).text }.to raise_error(TreeStump::Error, /without its source/)
+      end
     end
 
     describe "#inspect" do
@@ -349,7 +1204,7 @@ RSpec.describe TreeStump do
     end
 
     it "detect wrong query" do
-      expect { parser.build_query("(invalid_node)") }.to raise_error(TreeStump::Error)
+      expect { parser.build_query("(invalid_node)") }.to raise_error(TreeStump::QueryError)
     end
 
     it "#start_byte_for_pattern" do
@@ -406,6 +1261,26 @@ RSpec.describe TreeStump do
       query = parser.build_query(query_str)
       expect(query.is_pattern_guaranteed_at_step(0)).to be_falsey
     end
+
+    it "#general_predicates" do
+      query = parser.build_query('((constant) @name (#rails-model? @name "strict") (#match? @name "^A"))')
+      expect(query.general_predicates(0)).to eq([TreeStump::QueryPredicate.new("rails-model?", [0, "strict"])])
+    end
+
+    it "#property_settings" do
+      query = parser.build_query('((constant) @name (#set! kind "class"))')
+      expect(query.property_settings(0)).to eq([TreeStump::QueryProperty.new("kind", "class", nil)])
+    end
+
+    it "#property_predicates" do
+      query = parser.build_query('((constant) @name (#is-not? local))')
+      expect(query.property_predicates(0)).to eq([[TreeStump::QueryProperty.new("local", nil, nil), false]])
+    end
+
+    it "raises on an out of range pattern index" do
+      query = parser.build_query(query_str)
+      expect { query.general_predicates(1) }.to raise_error(TreeStump::Error)
+    end
   end
 
   describe "TreeStump::QueryCursor" do
@@ -460,6 +1335,79 @@ RSpec.describe TreeStump do
       expect(result[0].captures[0].node.utf8_text(source)).to eq("Hoge")
     end
 
+    describe "predicates" do
+      def matched_names(query_str)
+        query = parser.build_query(query_str)
+        root_node = parser.parse(source).root_node
+        TreeStump::QueryCursor.new.matches(query, root_node, source).map do |m|
+          m.captures[0].node.utf8_text(source)
+        end
+      end
+
+      it "evaluates #eq?" do
+        expect(matched_names('((class name: (constant) @name) (#eq? @name "Bar"))')).to eq(["Bar"])
+      end
+
+      it "evaluates #not-eq?" do
+        expect(matched_names('((class name: (constant) @name) (#not-eq? @name "Bar"))')).to eq(["Hoge"])
+      end
+
+      it "evaluates #any-of?" do
+        expect(matched_names('((class name: (constant) @name) (#any-of? @name "Foo" "Bar"))')).to eq(["Bar"])
+      end
+
+      it "evaluates #match? with Ruby regular expressions" do
+        expect(matched_names('((class name: (constant) @name) (#match? @name "^B"))')).to eq(["Bar"])
+        expect(matched_names('((class name: (constant) @name) (#match? @name "^(?=H)"))')).to eq(["Hoge"])
+      end
+
+      it "evaluates #not-match?" do
+        expect(matched_names('((class name: (constant) @name) (#not-match? @name "^B"))')).to eq(["Hoge"])
+      end
+
+      it "raises on an invalid regular expression" do
+        expect { parser.build_query('((constant) @name (#match? @name "("))') }.to raise_error(TreeStump::Error)
+      end
+
+      context "with custom predicates" do
+        let(:query) { parser.build_query('((class name: (constant) @name) (#starts-with? @name "B"))') }
+        let(:root_node) { parser.parse(source).root_node }
+
+        it "calls the block given to Query#define_predicate" do
+          calls = []
+          query.define_predicate("starts-with?") do |node, args, src|
+            calls << [node.kind, args, src]
+            node.utf8_text(src).start_with?(args[0])
+          end
+
+          result = TreeStump::QueryCursor.new.matches(query, root_node, source).map do |m|
+            m.captures[0].node.utf8_text(source)
+          end
+          expect(result).to eq(["Bar"])
+          expect(calls).to eq([["constant", ["B"], source], ["constant", ["B"], source]])
+        end
+
+        it "raises QueryError for unknown predicates" do
+          expect {
+            TreeStump::QueryCursor.new.matches(query, root_node, source).to_a
+          }.to raise_error(TreeStump::QueryError, /starts-with\?/)
+        end
+
+        it "ignores unknown predicates when requested" do
+          query.set_ignore_unknown_predicates(true)
+          expect(query.ignore_unknown_predicates).to be_truthy
+          expect(TreeStump::QueryCursor.new.matches(query, root_node, source).to_a.size).to eq(2)
+        end
+
+        it "propagates errors raised in the block" do
+          query.define_predicate("starts-with?") { raise ArgumentError, "boom" }
+          expect {
+            TreeStump::QueryCursor.new.matches(query, root_node, source).to_a
+          }.to raise_error(ArgumentError, "boom")
+        end
+      end
+    end
+
     it "can match query without block" do
       query = parser.build_query(query_str)
       query_cursor = TreeStump::QueryCursor.new
@@ -478,5 +1426,233 @@ RSpec.describe TreeStump do
       expect(result[0].captures[0].node.utf8_text(source)).to eq("Hoge")
       expect(indexes).to eq([0, 1])
     end
+
+    it "can match query without passing source" do
+      query = parser.build_query(query_str)
+      root_node = parser.parse(source).root_node
+      names = TreeStump::QueryCursor.new.matches(query, root_node).map { |m| m.captures[0].node.text }
+      expect(names).to eq(%w[Hoge Bar])
+
+      captures = TreeStump::QueryCursor.new.captures(query, root_node).map { |m, i| m.captures[i].node.text }
+      expect(captures).to eq(%w[Hoge Bar])
+    end
+
+    it "raises Error when matching without source on a tree without source" do
+      query = parser.build_query(query_str)
+      tree = parser.parse(source)
+      tree.edit(TreeStump::InputEdit.new(0, 0, 0, TreeStump::Point.new(0, 0), TreeStump::Point.new(0, 0), TreeStump::Point.new(0, 0)))
+      expect { TreeStump::QueryCursor.new.matches(query, tree.root_node).to_a }
+        .to raise_error(TreeStump::Error, /without its source/)
+    end
+
+    it "stops matching when the block breaks" do
+      query = parser.build_query('((class name: (constant) @class_name) (#counted? @class_name))')
+      calls = 0
+      query.define_predicate("counted?") { calls += 1 }
+      query_cursor = TreeStump::QueryCursor.new
+      root_node = parser.parse(source).root_node
+
+      first = query_cursor.matches(query, root_node, source) { |m| break m }
+      expect(first.captures[0].node.utf8_text(source)).to eq("Hoge")
+      expect(calls).to eq(1)
+    end
+
+    it "produces matches on demand through Enumerator#next" do
+      query = parser.build_query(query_str)
+      query_cursor = TreeStump::QueryCursor.new
+      root_node = parser.parse(source).root_node
+      enum = query_cursor.matches(query, root_node, source)
+
+      expect(enum.next.captures[0].node.utf8_text(source)).to eq("Hoge")
+      expect(enum.next.captures[0].node.utf8_text(source)).to eq("Bar")
+      expect { enum.next }.to raise_error(StopIteration)
+    end
+
+    it "applies cursor settings changed while matching to the next run" do
+      query = parser.build_query(query_str)
+      query_cursor = TreeStump::QueryCursor.new
+      root_node = parser.parse(source).root_node
+
+      names = []
+      query_cursor.matches(query, root_node, source) do |m|
+        query_cursor.set_max_start_depth(0)
+        names << m.captures[0].node.utf8_text(source)
+      end
+      expect(names).to eq(%w[Hoge Bar])
+      expect(query_cursor.matches(query, root_node, source).to_a).to be_empty
+    end
+
+    it "releases the cursor and query when an enumerator is abandoned" do
+      query = parser.build_query(query_str)
+      query_cursor = TreeStump::QueryCursor.new
+      root_node = parser.parse(source).root_node
+
+      enum = query_cursor.matches(query, root_node, source)
+      expect(enum.next.captures[0].node.utf8_text(source)).to eq("Hoge")
+      enum = nil
+      GC.start
+
+      names = []
+      query_cursor.matches(query, root_node, source) { |m| names << m.captures[0].node.utf8_text(source) }
+      expect(names).to eq(%w[Hoge Bar])
+    end
+
+    it "raises Error when the query is changed while matching" do
+      query = parser.build_query(query_str)
+      query_cursor = TreeStump::QueryCursor.new
+      root_node = parser.parse(source).root_node
+
+      expect {
+        query_cursor.matches(query, root_node, source) { query.disable_pattern(0) }
+      }.to raise_error(TreeStump::Error, /Query is in use/)
+
+      query.disable_pattern(0)
+      expect(query_cursor.matches(query, root_node, source).to_a).to be_empty
+    end
+
+    it "can run queries in multiple threads" do
+      query = parser.build_query(query_str)
+      root_node = parser.parse(source).root_node
+
+      names = 4.times.map do
+        Thread.new do
+          TreeStump::QueryCursor.new.matches(query, root_node, source).map { |m| m.captures[0].node.utf8_text(source) }
+        end
+      end.map(&:value)
+      expect(names).to all(eq(%w[Hoge Bar]))
+    end
+
+    describe "#captures" do
+      let(:query_str) do
+        <<~QUERY
+        (class name: (constant) @class_name)
+        (method name: (identifier) @method_name)
+        QUERY
+      end
+
+      it "yields captures in document order" do
+        query = parser.build_query(query_str)
+        query_cursor = TreeStump::QueryCursor.new
+        root_node = parser.parse(source).root_node
+
+        result = []
+        query_cursor.captures(query, root_node, source) do |m, capture_index|
+          expect(m).to be_a(TreeStump::QueryMatch)
+          capture = m.captures[capture_index]
+          result << [query.capture_names[capture.index], capture.node.utf8_text(source)]
+        end
+        expect(result).to eq([
+          ["class_name", "Hoge"],
+          ["method_name", "hello"],
+          ["method_name", "foo"],
+          ["class_name", "Bar"],
+          ["method_name", "bar"],
+        ])
+      end
+
+      it "returns Enumerator when no block is given" do
+        query = parser.build_query(query_str)
+        query_cursor = TreeStump::QueryCursor.new
+        root_node = parser.parse(source).root_node
+
+        enum = query_cursor.captures(query, root_node, source)
+        expect(enum).to be_a(Enumerator)
+        expect(enum.map { |m, i| m.captures[i].node.utf8_text(source) }).to eq(%w[Hoge hello foo Bar bar])
+      end
+
+      it "filters captures with predicates" do
+        query = parser.build_query('((method name: (identifier) @method_name) (#not-eq? @method_name "foo"))')
+        query_cursor = TreeStump::QueryCursor.new
+        root_node = parser.parse(source).root_node
+
+        names = query_cursor.captures(query, root_node, source).map { |m, i| m.captures[i].node.utf8_text(source) }
+        expect(names).to eq(%w[hello bar])
+      end
+    end
+  end
+
+  describe "TreeStump::Highlighter" do
+    let(:highlighter) { TreeStump::Highlighter.load("ruby", tree_sitter_ruby_queries_dir) }
+    let(:source) { %(def hello\n  puts "<hi>" # greet\nend\n) }
+
+    it "loads queries of the language" do
+      expect(highlighter.language_name).to eq("ruby")
+      expect(highlighter.highlight_names).to include("keyword", "string", "comment")
+      expect(highlighter.highlight_names).not_to include(a_string_starting_with("local."))
+    end
+
+    it "returns highlight events covering the whole source" do
+      events = highlighter.highlight(source)
+      expect(events.map(&:type).uniq).to contain_exactly(:start, :source, :end)
+      expect(events.count { |e| e.type == :start }).to eq(events.count { |e| e.type == :end })
+
+      text = events.select { |e| e.type == :source }.map { |e| source.byteslice(e.start_byte...e.end_byte) }.join
+      expect(text).to eq(source)
+    end
+
+    it "restricts highlights to the given names" do
+      highlighter = TreeStump::Highlighter.load("ruby", tree_sitter_ruby_queries_dir, highlight_names: ["comment"])
+      names = highlighter.highlight(source).select { |e| e.type == :start }.map(&:name)
+      expect(names).to eq(["comment"])
+    end
+
+    it "raises QueryError on broken highlights query" do
+      expect { TreeStump::Highlighter.new("ruby", highlights: "(foo") }.to raise_error(TreeStump::QueryError)
+    end
+
+    it "renders HTML" do
+      html = highlighter.to_html(source)
+      expect(html).to include(%(<span class="keyword">def</span>))
+      expect(html).to include("&lt;hi&gt;")
+      expect(html).to include(%(<span class="comment"># greet</span>))
+    end
+
+    it "renders ANSI escape sequences" do
+      ansi = highlighter.to_ansi(source, theme: { "keyword" => "31", "comment" => "90" })
+      expect(ansi).to include("\e[31mdef\e[0m")
+      expect(ansi).to include("\e[90m# greet\e[0m")
+      expect(ansi.gsub(/\e\[[\d;]*m/, "")).to eq(source)
+    end
+  end
+
+  describe "TreeStump::Tagger" do
+    let(:tagger) { TreeStump::Tagger.load("ruby", tree_sitter_ruby_queries_dir) }
+    let(:source) do
+      <<~RUBY
+      # Says hello
+      # to everyone
+      def hello
+        puts "hi"
+      end
+
+      class Greeter
+      end
+      RUBY
+    end
+
+    it "extracts definitions with docs" do
+      definitions = tagger.tags(source).select(&:definition?)
+      expect(definitions.map { |t| [t.kind, t.name] }).to include(["method", "hello"], ["class", "Greeter"])
+
+      hello = definitions.find { |t| t.name == "hello" }
+      expect(hello.docs).to eq("Says hello\nto everyone")
+      expect(hello.line).to eq("def hello")
+      expect(hello.row).to eq(2)
+      expect(hello.name_range.start_byte).to eq(source.index("hello", source.index("def")))
+      expect(source.byteslice(hello.byte_range)).to start_with("def hello")
+    end
+
+    it "extracts references" do
+      references = tagger.tags(source).select(&:reference?)
+      expect(references.map { |t| [t.kind, t.name] }).to include(["call", "puts"])
+    end
+
+    it "raises QueryError on broken tags query" do
+      expect { TreeStump::Tagger.new("ruby", "(method name: (_) @name") }.to raise_error(TreeStump::QueryError)
+    end
+
+    it "raises Error on unregistered language" do
+      expect { TreeStump::Tagger.new("unknown", "") }.to raise_error(TreeStump::Error)
+    end
   end
 end
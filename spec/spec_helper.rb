@@ -54,6 +54,10 @@ RSpec.configure do |config|
     def tree_sitter_ruby_path
       File.join(project_root, 'tree-sitter-ruby', 'libtree-sitter-ruby.so')
     end
+
+    def tree_sitter_ruby_queries_dir
+      File.join(project_root, 'tree-sitter-ruby', 'queries')
+    end
   end
 
   config.extend Helpers